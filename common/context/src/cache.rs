@@ -0,0 +1,150 @@
+use common::{
+    chrono,
+    futures_util::{future, StreamExt as _},
+    serde::de::DeserializeOwned,
+    tokio, tracing,
+};
+use kube::{
+    k8s_openapi::api::core::v1::{Namespace, Pod},
+    kube_runtime::{
+        runtime::{
+            reflector::{self, Store},
+            watcher, WatchStreamExt as _,
+        },
+        Api, Client as KubeClient, Resource,
+    },
+};
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc,
+    },
+};
+
+/// Reflector-backed copy of pods and namespaces so list requests don't hit the API server.
+#[derive(Clone)]
+pub struct KubeCache {
+    /// Namespace the pod cache is limited to, `None` caches every namespace.
+    pub namespace: Option<String>,
+    pub pods: Store<Pod>,
+    /// Only cached cluster-wide, a namespaced deployment may not be allowed to list namespaces.
+    pub namespaces: Option<Store<Namespace>>,
+    pods_sync: Arc<SyncState>,
+    namespaces_sync: Arc<SyncState>,
+}
+
+#[derive(Default)]
+struct SyncState {
+    ready: AtomicBool,
+    last_event_ms: AtomicI64,
+}
+
+impl SyncState {
+    fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// `None` until the initial sync, before it there was no event to measure from.
+    fn staleness_secs(&self) -> Option<f64> {
+        if !self.is_ready() {
+            return None;
+        }
+        let last_event_ms = self.last_event_ms.load(Ordering::Relaxed);
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        Some((now_ms - last_event_ms).max(0) as f64 / 1000.0)
+    }
+}
+
+impl KubeCache {
+    pub fn start(kube_client: KubeClient, namespace: Option<String>) -> Self {
+        let pod_api: Api<Pod> = match &namespace {
+            Some(ns) => Api::namespaced(kube_client.clone(), ns),
+            None => Api::all(kube_client.clone()),
+        };
+        let pods_sync = Arc::new(SyncState::default());
+        let pods = spawn_reflector(pod_api, pods_sync.clone());
+
+        let namespaces_sync = Arc::new(SyncState::default());
+        let namespaces = match namespace {
+            Some(_) => None,
+            None => Some(spawn_reflector(
+                Api::<Namespace>::all(kube_client),
+                namespaces_sync.clone(),
+            )),
+        };
+
+        Self {
+            namespace,
+            pods,
+            namespaces,
+            pods_sync,
+            namespaces_sync,
+        }
+    }
+
+    pub fn pods_ready(&self) -> bool {
+        self.pods_sync.is_ready()
+    }
+
+    pub fn namespaces_ready(&self) -> bool {
+        self.namespaces.is_some() && self.namespaces_sync.is_ready()
+    }
+
+    /// Whether the initial sync of every cached resource has completed.
+    pub fn is_ready(&self) -> bool {
+        self.pods_ready() && (self.namespaces.is_none() || self.namespaces_ready())
+    }
+
+    /// Whether the pod cache holds `namespace`, `None` asks for every namespace.
+    pub fn covers(&self, namespace: Option<&str>) -> bool {
+        match (&self.namespace, namespace) {
+            (None, _) => true,
+            (Some(scope), Some(ns)) => scope == ns,
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Seconds since the last watch event per cached resource, resources still syncing are
+    /// left out.
+    pub fn staleness_secs(&self) -> Vec<(&'static str, f64)> {
+        let mut staleness = vec![("pods", self.pods_sync.staleness_secs())];
+        if self.namespaces.is_some() {
+            staleness.push(("namespaces", self.namespaces_sync.staleness_secs()));
+        }
+        staleness
+            .into_iter()
+            .filter_map(|(resource, secs)| Some((resource, secs?)))
+            .collect()
+    }
+}
+
+fn spawn_reflector<K>(api: Api<K>, sync: Arc<SyncState>) -> Store<K>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+{
+    let (reader, writer) = reflector::store();
+    let stream = reflector::reflector(
+        writer,
+        watcher(api, watcher::Config::default()).default_backoff(),
+    );
+
+    tokio::spawn(stream.for_each(move |event| {
+        match event {
+            Ok(event) => {
+                if let watcher::Event::InitDone = event {
+                    sync.ready.store(true, Ordering::Relaxed);
+                    tracing::info!("Cache synced for {}", K::kind(&()));
+                }
+                let now_ms = chrono::Utc::now().timestamp_millis();
+                sync.last_event_ms.store(now_ms, Ordering::Relaxed);
+            }
+            Err(err) => {
+                tracing::warn!("Cache watch error for {}, retrying: {}", K::kind(&()), err);
+            }
+        }
+        future::ready(())
+    }));
+
+    reader
+}
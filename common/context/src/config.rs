@@ -0,0 +1,133 @@
+use common::dotenv;
+use std::str::FromStr;
+
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    pub debug_image: String,
+    pub debug_shell: String,
+    pub debug_timeout_secs: u64,
+    pub list_page_size_default: u32,
+    pub list_page_size_max: u32,
+    pub cache_enabled: bool,
+    /// Limits the pod cache to one namespace, every namespace is cached when unset.
+    pub cache_namespace: Option<String>,
+    pub broadcast_parallelism: usize,
+    pub broadcast_timeout_secs: u64,
+    /// Bytes of stdout and of stderr kept per pod.
+    pub broadcast_output_limit: usize,
+    pub multi_pane_max: usize,
+    /// Header set by the authenticating proxy in front of the service. It's trusted as is, so
+    /// the service must only be reachable through a proxy that strips it from client requests.
+    pub auth_user_header: String,
+    /// Header with the user's comma-separated groups, set by the same proxy.
+    pub auth_groups_header: String,
+    /// Rejects requests without the user header instead of treating them as anonymous.
+    pub auth_required: bool,
+    /// How long a session outlives its owner's connection, 0 ends it right away.
+    pub session_grace_secs: u64,
+    /// Output kept per session to replay when the owner resumes it.
+    pub session_scrollback_bytes: usize,
+    /// Frames the session owner may fall behind before the exec stream is paused.
+    pub session_output_window: u64,
+    /// Ends sessions without input for this long, 0 disables it.
+    pub session_idle_timeout_secs: u64,
+    /// Ends sessions open for this long, 0 disables it.
+    pub session_max_duration_secs: u64,
+    /// How long before either limit a warning is written into the terminal.
+    pub session_warning_secs: u64,
+    /// `TERM` of session shells unless the request sets one.
+    pub session_term: String,
+    /// `LANG` of session shells unless the request sets one, the image's own when unset.
+    pub session_lang: Option<String>,
+    /// Set in every session shell, from comma-separated `KEY=VALUE` pairs such as
+    /// `KUBECONFIG=/kube/config`. A malformed list fails at startup.
+    pub session_env: Vec<(String, String)>,
+    /// Starting directory of session shells unless the request sets one, the image's when unset.
+    pub session_workdir: Option<String>,
+    /// Concurrent terminal sessions, viewers of shared sessions, pod watches and broadcasts, 0
+    /// means unlimited. Watches and broadcasts only count against the global and per-user limits.
+    pub max_sessions: usize,
+    pub max_sessions_per_user: usize,
+    pub max_sessions_per_pod: usize,
+    /// Token refill rate of the list endpoints per user, 0 disables rate limiting.
+    pub list_rate_per_sec: f64,
+    pub list_rate_burst: f64,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AppConfig {
+    pub fn new() -> Self {
+        dotenv::dotenv().ok();
+        Self {
+            debug_image: env_or("DEBUG_CONTAINER_IMAGE", "busybox:1.36".to_string()),
+            debug_shell: env_or("DEBUG_CONTAINER_SHELL", "sh".to_string()),
+            debug_timeout_secs: env_or("DEBUG_CONTAINER_TIMEOUT_SECS", 60),
+            list_page_size_default: env_or("LIST_PAGE_SIZE_DEFAULT", 20),
+            list_page_size_max: env_or("LIST_PAGE_SIZE_MAX", 200),
+            cache_enabled: env_or("KUBE_CACHE_ENABLED", false),
+            cache_namespace: env_non_empty("KUBE_CACHE_NAMESPACE"),
+            broadcast_parallelism: env_or("BROADCAST_PARALLELISM", 10),
+            broadcast_timeout_secs: env_or("BROADCAST_TIMEOUT_SECS", 60),
+            broadcast_output_limit: env_or("BROADCAST_OUTPUT_LIMIT", 64 * 1024),
+            multi_pane_max: env_or("MULTI_PANE_MAX", 16),
+            auth_user_header: env_or("AUTH_USER_HEADER", "X-Forwarded-User".to_string()),
+            auth_groups_header: env_or("AUTH_GROUPS_HEADER", "X-Forwarded-Groups".to_string()),
+            auth_required: env_or("AUTH_REQUIRED", false),
+            session_grace_secs: env_or("SESSION_GRACE_SECS", 60),
+            session_scrollback_bytes: env_or("SESSION_SCROLLBACK_BYTES", 256 * 1024),
+            session_output_window: env_or("SESSION_OUTPUT_WINDOW", 64),
+            session_idle_timeout_secs: env_or("SESSION_IDLE_TIMEOUT_SECS", 30 * 60),
+            session_max_duration_secs: env_or("SESSION_MAX_DURATION_SECS", 8 * 60 * 60),
+            session_warning_secs: env_or("SESSION_WARNING_SECS", 60),
+            session_term: env_or("SESSION_TERM", "xterm".to_string()),
+            session_lang: env_non_empty("SESSION_LANG"),
+            session_env: env_non_empty("SESSION_ENV")
+                .map(|list| {
+                    parse_env_list(&list).unwrap_or_else(|err| panic!("SESSION_ENV: {err}"))
+                })
+                .unwrap_or_default(),
+            session_workdir: env_non_empty("SESSION_WORKDIR"),
+            max_sessions: env_or("MAX_SESSIONS", 500),
+            max_sessions_per_user: env_or("MAX_SESSIONS_PER_USER", 10),
+            max_sessions_per_pod: env_or("MAX_SESSIONS_PER_POD", 20),
+            list_rate_per_sec: env_or("LIST_RATE_PER_SEC", 5.0),
+            list_rate_burst: env_or("LIST_RATE_BURST", 20.0),
+        }
+    }
+}
+
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+pub fn env_non_empty(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+/// Parses `KEY=VALUE,KEY=VALUE`, keys are letters, digits and `_` not starting with a digit.
+/// Values can't contain commas.
+pub fn parse_env_list(list: &str) -> Result<Vec<(String, String)>, String> {
+    list.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("{pair:?} isn't KEY=VALUE."))?;
+            let key = key.trim();
+            let valid = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                return Err(format!("{key:?} isn't a valid variable name."));
+            }
+            Ok((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
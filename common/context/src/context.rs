@@ -1,3 +1,4 @@
+use crate::{cache::KubeCache, config::AppConfig};
 use common::anyhow;
 use kube::{init_kube_client, kube_runtime};
 use kube_runtime::Client as KubeClient;
@@ -6,12 +7,23 @@ use kube_runtime::Client as KubeClient;
 pub struct Context {
     #[allow(dead_code)]
     pub kube_client: KubeClient,
+    pub config: AppConfig,
+    /// Present when `KUBE_CACHE_ENABLED` is set, list requests are then served from memory.
+    pub cache: Option<KubeCache>,
 }
 
 impl Context {
     pub async fn new() -> Result<Self, anyhow::Error> {
+        let kube_client = init_kube_client().await?;
+        let config = AppConfig::new();
+        let cache = config
+            .cache_enabled
+            .then(|| KubeCache::start(kube_client.clone(), config.cache_namespace.clone()));
+
         Ok(Self {
-            kube_client: init_kube_client().await?,
+            kube_client,
+            config,
+            cache,
         })
     }
 }
@@ -4,6 +4,16 @@ pub struct AxumErr(anyhow::Error);
 // Convert AxumErr into axum response.
 impl axum::response::IntoResponse for AxumErr {
     fn into_response(self) -> axum::response::Response {
+        if let Some(err) = self.0.downcast_ref::<HttpErr>() {
+            let rsp =
+                Rsp::<()>::error(err.status.as_u16(), &err.message).with_http_status(err.status);
+            let mut response = rsp.into_response();
+            if let Some(headers) = &err.headers {
+                response.headers_mut().extend(headers.clone());
+            }
+            return response;
+        }
+
         (
             axum::http::StatusCode::INTERNAL_SERVER_ERROR,
             format!("Something went wrong: {}", self.0),
@@ -12,6 +22,30 @@ impl axum::response::IntoResponse for AxumErr {
     }
 }
 
+/// An error the client can act on, rendered as `Rsp` with its own HTTP status instead of 500.
+#[derive(Error, Debug)]
+#[error("{message}")]
+pub struct HttpErr {
+    pub status: StatusCode,
+    pub message: String,
+    pub headers: Option<HeaderMap>,
+}
+
+impl HttpErr {
+    pub fn new(status: StatusCode, message: &str) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+            headers: None,
+        }
+    }
+
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = Some(headers);
+        self
+    }
+}
+
 // This enables using `?` on functions that return `Result<_, anyhow::Error>` to turn them into
 // `Result<_, AxumErr>`. That way you don't need to do that manually.
 impl<E> From<E> for AxumErr
@@ -23,8 +57,9 @@ where
     }
 }
 
+use crate::rsp::Rsp;
 use axum::{
-    http::{header::ToStrError, StatusCode},
+    http::{header::ToStrError, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
 };
 use common::{anyhow, axum, serde_json, thiserror, tracing};
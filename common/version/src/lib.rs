@@ -17,3 +17,4 @@ pub use tokio_tungstenite;
 pub use toml;
 pub use tracing;
 pub use tracing_appender;
+pub use uuid;
@@ -0,0 +1,85 @@
+//! Throughput of the kube output path, from the exec websocket to encoded browser frames.
+//! A fake API server in the same process streams stdout frames of a fixed size.
+//!
+//! cargo bench -p pod_exec --bench pipeline
+
+use common::{
+    futures_util::SinkExt as _,
+    tokio::{self, net::TcpListener, sync::mpsc},
+    tokio_tungstenite::{self, tungstenite::Message},
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use pod_exec::{
+    msg_handle::{handle_websocket, STD_OUTPUT_PREFIX_NORMAL},
+    pipeline::{encode_frame, forward_frames, COALESCE_MAX_BYTES, FRAME_CHANNEL_CAPACITY},
+};
+
+/// Bytes of terminal output streamed per iteration.
+const OUTPUT_BYTES: usize = 4 * 1024 * 1024;
+
+/// Serves one exec stream of `OUTPUT_BYTES` split into `frame_size` stdout frames.
+async fn fake_exec_server(frame_size: usize) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let mut frame = vec![b'x'; frame_size + 1];
+        frame[0] = STD_OUTPUT_PREFIX_NORMAL;
+        for _ in 0..OUTPUT_BYTES / frame_size {
+            ws.feed(Message::Binary(frame.clone())).await.unwrap();
+        }
+        ws.send(Message::Close(None)).await.unwrap();
+    });
+    format!("ws://{addr}")
+}
+
+/// Returns the size of the encoded frames the browser would receive.
+async fn run_pipeline(url: String) -> usize {
+    let (mut kube_ws_stream, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    let (_tx_web, mut rx_web) = mpsc::channel::<String>(1);
+    let (tx_kube, rx_kube) = mpsc::channel(FRAME_CHANNEL_CAPACITY);
+    tokio::spawn(async move {
+        let mut closed = false;
+        handle_websocket(
+            &mut kube_ws_stream,
+            &mut rx_web,
+            &tx_kube,
+            &mut closed,
+            None,
+        )
+        .await;
+    });
+
+    let mut encoded = 0;
+    forward_frames(rx_kube, COALESCE_MAX_BYTES, |frame| {
+        encoded += encode_frame(&frame).len();
+        async { true }
+    })
+    .await;
+    encoded
+}
+
+fn kube_output_throughput(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("kube_output");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(OUTPUT_BYTES as u64));
+
+    for frame_size in [16, 1024, 16 * 1024] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(frame_size),
+            &frame_size,
+            |b, &frame_size| {
+                b.to_async(&rt).iter(|| async move {
+                    let url = fake_exec_server(frame_size).await;
+                    run_pipeline(url).await
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, kube_output_throughput);
+criterion_main!(benches);
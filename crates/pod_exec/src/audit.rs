@@ -0,0 +1,51 @@
+//! Session audit events, logged under the `audit` target so they can be filtered and shipped
+//! separately, e.g. `RUST_LOG=info,audit=info`.
+
+use common::tracing;
+
+use crate::{auth::User, protocol::Signal, session::SharedSession};
+
+pub fn session_started(session: &SharedSession) {
+    tracing::info!(
+        target: "audit",
+        session = %session.id,
+        user = %session.owner,
+        namespace = %session.coords.namespace,
+        pod = %session.coords.pod,
+        container = %session.coords.container,
+        "session started"
+    );
+}
+
+pub fn session_joined(session: &SharedSession, user: &User) {
+    tracing::info!(
+        target: "audit",
+        session = %session.id,
+        user = %user.0,
+        "session joined"
+    );
+}
+
+pub fn session_signalled(session: &SharedSession, user: &User, signal: Signal) {
+    tracing::info!(
+        target: "audit",
+        session = %session.id,
+        user = %user.0,
+        signal = signal.kill_name(),
+        "session signalled"
+    );
+}
+
+pub fn session_ended(session: &SharedSession, reason: &str, duration_secs: u64) {
+    tracing::info!(
+        target: "audit",
+        session = %session.id,
+        user = %session.owner,
+        namespace = %session.coords.namespace,
+        pod = %session.coords.pod,
+        container = %session.coords.container,
+        reason,
+        duration_secs,
+        "session ended"
+    );
+}
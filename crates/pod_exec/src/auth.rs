@@ -0,0 +1,93 @@
+use common::{
+    anyhow,
+    axum::{
+        async_trait,
+        extract::FromRequestParts,
+        http::{request::Parts, StatusCode},
+    },
+};
+use context::context::Context;
+use util::err::{AxumErr, HttpErr};
+
+pub const ANONYMOUS: &str = "anonymous";
+
+/// The caller as identified by the authenticating proxy, see `AppConfig::auth_user_header`. The
+/// header is taken on trust, a client that reaches the service directly can claim any user.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct User(pub String);
+
+impl User {
+    pub fn is_anonymous(&self) -> bool {
+        self.0 == ANONYMOUS
+    }
+
+    /// Fails with 401 for anonymous callers, for features that only make sense per user.
+    pub fn require_authenticated(&self) -> Result<(), anyhow::Error> {
+        if self.is_anonymous() {
+            let message = "This requires an authenticated user.";
+            return Err(HttpErr::new(StatusCode::UNAUTHORIZED, message).into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for User
+where
+    S: Send + Sync,
+{
+    type Rejection = AxumErr;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ctx = parts
+            .extensions
+            .get::<Context>()
+            .ok_or_else(|| anyhow::anyhow!("Context extension is missing"))?;
+        let user = parts
+            .headers
+            .get(ctx.config.auth_user_header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty());
+
+        match user {
+            Some(user) => Ok(User(user.to_string())),
+            None if ctx.config.auth_required => {
+                let message = format!("Missing {} header.", ctx.config.auth_user_header);
+                Err(HttpErr::new(StatusCode::UNAUTHORIZED, &message).into())
+            }
+            None => Ok(User(ANONYMOUS.to_string())),
+        }
+    }
+}
+
+/// The caller's groups as forwarded by the proxy, see `AppConfig::auth_groups_header`. Only
+/// used to ask the API server what the caller may do, empty when the header is missing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Groups(pub Vec<String>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Groups
+where
+    S: Send + Sync,
+{
+    type Rejection = AxumErr;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ctx = parts
+            .extensions
+            .get::<Context>()
+            .ok_or_else(|| anyhow::anyhow!("Context extension is missing"))?;
+        let groups = parts
+            .headers
+            .get_all(ctx.config.auth_groups_header.as_str())
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|group| !group.is_empty())
+            .map(str::to_string)
+            .collect();
+        Ok(Groups(groups))
+    }
+}
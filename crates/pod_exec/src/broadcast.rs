@@ -0,0 +1,145 @@
+use common::{
+    anyhow,
+    axum::http::StatusCode,
+    futures_util::{stream, Stream, StreamExt as _},
+    tokio::{
+        self,
+        sync::mpsc,
+        time::{timeout_at, Duration, Instant},
+    },
+    tracing,
+};
+use context::context::Context;
+use kube::{
+    k8s_openapi::api::core::v1::Pod,
+    kube_runtime::{api::ListParams, Api},
+    ServiceAccountToken,
+};
+use util::err::HttpErr;
+
+use crate::{
+    connector::{exec_command, ContainerCoords},
+    model::{BroadcastEvent, BroadcastReq, BroadcastResult, BroadcastSummary},
+};
+
+/// Runs `req.command` on every running pod matching the selector, at most `parallelism` at a
+/// time. Results are streamed as they complete, followed by a summary.
+pub async fn broadcast_exec(
+    ctx: &Context,
+    namespace: &str,
+    req: BroadcastReq,
+) -> Result<impl Stream<Item = BroadcastEvent>, anyhow::Error> {
+    req.validate()?;
+
+    let lp = ListParams::default()
+        .labels(&req.label_selector)
+        .fields("status.phase=Running");
+    let pods = Api::<Pod>::namespaced(ctx.kube_client.clone(), namespace)
+        .list(&lp)
+        .await?;
+    let targets: Vec<ContainerCoords> = pods
+        .iter()
+        .filter(|p| p.metadata.deletion_timestamp.is_none())
+        .filter_map(|p| broadcast_target(p, req.container.as_deref()))
+        .collect();
+    if targets.is_empty() {
+        let message = format!("No running pod matches {}.", req.label_selector);
+        return Err(HttpErr::new(StatusCode::NOT_FOUND, &message).into());
+    }
+
+    let parallelism = req
+        .parallelism
+        .unwrap_or(ctx.config.broadcast_parallelism)
+        .clamp(1, ctx.config.broadcast_parallelism.max(1));
+    let timeout = Duration::from_secs(
+        req.timeout_secs
+            .unwrap_or(ctx.config.broadcast_timeout_secs)
+            .clamp(1, ctx.config.broadcast_timeout_secs.max(1)),
+    );
+    let output_limit = ctx.config.broadcast_output_limit;
+    tracing::info!(
+        "Broadcast {:?} to {} pods in {}",
+        req.command,
+        targets.len(),
+        namespace
+    );
+
+    let (tx, rx) = mpsc::channel(parallelism);
+    tokio::spawn(async move {
+        let sat = ServiceAccountToken::new();
+        let deadline = Instant::now() + timeout;
+        let mut summary = BroadcastSummary {
+            total: targets.len(),
+            ..Default::default()
+        };
+
+        let mut results = stream::iter(targets)
+            .map(|coords| {
+                let sat = &sat;
+                let command = &req.command;
+                async move {
+                    let output =
+                        timeout_at(deadline, exec_command(sat, &coords, command, output_limit))
+                            .await;
+                    (coords, output)
+                }
+            })
+            .buffer_unordered(parallelism);
+
+        while let Some((coords, output)) = results.next().await {
+            let mut result = BroadcastResult {
+                pod: coords.pod,
+                container: coords.container,
+                exit_code: None,
+                stdout: String::new(),
+                stderr: String::new(),
+                truncated: false,
+                error: None,
+            };
+            let timed_out = output.is_err();
+            match output {
+                Ok(Ok(output)) => {
+                    result.exit_code = output.exit_code;
+                    result.stdout = output.stdout;
+                    result.stderr = output.stderr;
+                    result.truncated = output.truncated;
+                    result.error = output.error;
+                }
+                Ok(Err(err)) => result.error = Some(err.to_string()),
+                Err(_) => result.error = Some("Timed out.".to_string()),
+            }
+
+            summary.record(&result, timed_out);
+            if tx.send(BroadcastEvent::Result(result)).await.is_err() {
+                tracing::info!("Broadcast client went away, cancelling remaining pods");
+                return;
+            }
+        }
+
+        tracing::info!(
+            "Broadcast done, {}/{} succeeded",
+            summary.succeeded,
+            summary.total
+        );
+        let _ = tx.send(BroadcastEvent::Summary(summary)).await;
+    });
+
+    Ok(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (event, rx))
+    }))
+}
+
+/// The requested container, or the pod's first one. Pods without the container are skipped.
+pub fn broadcast_target(pod: &Pod, container: Option<&str>) -> Option<ContainerCoords> {
+    let containers = &pod.spec.as_ref()?.containers;
+    let container = match container {
+        Some(name) => containers.iter().find(|c| c.name == name)?,
+        None => containers.first()?,
+    };
+
+    Some(ContainerCoords {
+        namespace: pod.metadata.namespace.clone()?,
+        pod: pod.metadata.name.clone()?,
+        container: container.name.clone(),
+    })
+}
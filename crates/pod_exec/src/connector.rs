@@ -1,17 +1,167 @@
+use crate::model::TerminalQuery;
+use crate::msg_handle::{STATUS_PREFIX, STD_OUTPUT_PREFIX_ERR, STD_OUTPUT_PREFIX_NORMAL};
+use crate::pipeline::Frame;
 use common::anyhow::{self, Result};
-use common::axum::extract::RawPathParams;
-use common::{tokio, tokio_tungstenite, tracing};
+use common::axum::{
+    async_trait,
+    extract::{FromRequestParts, Path},
+    http::{request::Parts, StatusCode},
+};
+use common::{futures_util::StreamExt as _, serde_json, tokio, tokio_tungstenite, tracing};
+use context::config::{parse_env_list, AppConfig};
+use kube::k8s_openapi::apimachinery::pkg::apis::meta::v1::Status;
 use kube::ServiceAccountToken;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::fmt;
 use tokio::net::TcpStream;
 use tokio_tungstenite::tungstenite::handshake::client::Request;
 use tokio_tungstenite::tungstenite::http::header::{
     CONNECTION, HOST, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE,
 };
+use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{connect_async_tls_with_config, Connector};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
-use util::url_https_builder;
+use util::{
+    err::{AxumErr, HttpErr},
+    url_https_builder,
+};
+
+pub const DEFAULT_SHELL: &str = "bash";
+
+/// Environment variable carrying the session id in the exec'd shell, see `signal`.
+pub const SESSION_MARKER_ENV: &str = "KUBE_TERM_SESSION";
+
+/// How the shell of a terminal is started.
+#[derive(Debug, Clone, Default)]
+pub struct TerminalOptions {
+    pub shell: String,
+    /// Resize frame sent ahead of any input, see `TerminalQuery`.
+    pub resize: Option<Frame>,
+    /// Set in order through `env`, a later variable wins over an earlier one of the same name.
+    pub env: Vec<(String, String)>,
+    pub workdir: Option<String>,
+}
+
+impl TerminalOptions {
+    /// Request settings over the config defaults, fails with 400 on an invalid size or
+    /// variable.
+    pub fn new(
+        shell: &str,
+        query: &TerminalQuery,
+        config: &AppConfig,
+    ) -> Result<Self, anyhow::Error> {
+        let bad_request = |message: String| HttpErr::new(StatusCode::BAD_REQUEST, &message);
+        let term = query.term.as_ref().unwrap_or(&config.session_term);
+        let mut env = vec![("TERM".to_string(), term.clone())];
+        if let Some(lang) = query.lang.as_ref().or(config.session_lang.as_ref()) {
+            env.push(("LANG".to_string(), lang.clone()));
+        }
+        env.extend(config.session_env.iter().cloned());
+        if let Some(list) = &query.env {
+            env.extend(parse_env_list(list).map_err(bad_request)?);
+        }
+
+        let workdir = query.workdir.as_ref().or(config.session_workdir.as_ref());
+        let values = env
+            .iter()
+            .map(|(_, value)| value.as_str())
+            .chain(workdir.map(String::as_str))
+            .chain([shell]);
+        for value in values {
+            if value.contains('\0') {
+                return Err(bad_request(format!("{value:?} contains a NUL byte.")).into());
+            }
+        }
+        if shell.is_empty() {
+            return Err(bad_request("The shell can't be empty.".to_string()).into());
+        }
+
+        Ok(Self {
+            shell: shell.to_string(),
+            resize: query.resize_frame()?,
+            env,
+            workdir: workdir.cloned(),
+        })
+    }
+
+    /// Arguments of the exec: `env` with the variables, the session marker last, then the
+    /// shell, started from `sh -c` after a `cd` when there is a working directory.
+    pub fn command(&self, session_id: Option<&str>) -> Vec<String> {
+        let marker = session_id.map(|id| (SESSION_MARKER_ENV.to_string(), id.to_string()));
+        let mut command = vec!["env".to_string()];
+        command.extend(
+            self.env
+                .iter()
+                .chain(marker.as_ref())
+                .map(|(key, value)| format!("{key}={value}")),
+        );
+        match &self.workdir {
+            Some(workdir) => command.extend([
+                "sh".to_string(),
+                "-c".to_string(),
+                format!(
+                    "cd -- {} && exec {}",
+                    shell_quote(workdir),
+                    shell_quote(&self.shell)
+                ),
+            ]),
+            None => command.push(self.shell.clone()),
+        }
+        command
+    }
+}
+
+/// Quotes `value` as a single POSIX shell word.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Query string where every value is percent-encoded, keys are trusted.
+#[derive(Debug, Default)]
+pub struct QueryBuilder {
+    query: String,
+}
+
+impl QueryBuilder {
+    pub fn param(mut self, key: &str, value: impl fmt::Display) -> Self {
+        self.query
+            .push(if self.query.is_empty() { '?' } else { '&' });
+        self.query.push_str(key);
+        self.query.push('=');
+        self.query.push_str(&encode_query_value(&value.to_string()));
+        self
+    }
+
+    /// Repeats `key` for each value, in order.
+    pub fn params<I>(self, key: &str, values: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: fmt::Display,
+    {
+        values
+            .into_iter()
+            .fold(self, |builder, value| builder.param(key, value))
+    }
+
+    pub fn build(self) -> String {
+        self.query
+    }
+}
+
+/// Percent-encodes a query parameter value, only RFC 3986 unreserved characters are kept. The
+/// result is a valid path segment as well.
+pub fn encode_query_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct ContainerCoordsOptional {
@@ -23,27 +173,108 @@ pub struct ContainerCoordsOptional {
     pub container: Option<String>,
 }
 
-#[derive(Debug, Default, Serialize)]
+/// Path of the terminal routes. Extracted with the names checked against the Kubernetes
+/// naming rules, an invalid one is rejected with 400 before any request to the API server.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ContainerCoords {
     pub namespace: String,
     pub pod: String,
+    /// Missing on the debug route, the ephemeral container is named when it is created.
+    #[serde(default)]
     pub container: String,
 }
 
 impl ContainerCoords {
-    pub fn populate_from_raw_path_params(mut self, raw_path_params: &RawPathParams) -> Self {
-        for (key, value) in raw_path_params.iter() {
-            match key {
-                "namespace" => value.clone_into(&mut self.namespace),
-                "pod" => value.clone_into(&mut self.pod),
-                "container" => value.clone_into(&mut self.container),
-                _ => todo!(),
-            }
+    pub fn validate(&self) -> Result<()> {
+        validate_name("namespace", &self.namespace, is_dns1123_label)?;
+        validate_name("pod", &self.pod, is_dns1123_subdomain)?;
+        if !self.container.is_empty() {
+            validate_name("container", &self.container, is_dns1123_label)?;
         }
-        self
+        Ok(())
     }
 }
 
+#[async_trait]
+impl<S> FromRequestParts<S> for ContainerCoords
+where
+    S: Send + Sync,
+{
+    type Rejection = AxumErr;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let coords: ContainerCoords = extract_path(parts, state).await?;
+        coords.validate()?;
+        Ok(coords)
+    }
+}
+
+/// Path of the routes scoped to a namespace, e.g. `/namespace/:namespace/workload`. Extracted
+/// and validated like `ContainerCoords`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NamespaceCoords {
+    pub namespace: String,
+}
+
+impl NamespaceCoords {
+    pub fn validate(&self) -> Result<()> {
+        validate_name("namespace", &self.namespace, is_dns1123_label)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for NamespaceCoords
+where
+    S: Send + Sync,
+{
+    type Rejection = AxumErr;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let coords: NamespaceCoords = extract_path(parts, state).await?;
+        coords.validate()?;
+        Ok(coords)
+    }
+}
+
+/// Extracts the path parameters, a path that doesn't deserialize is rejected with 400.
+pub async fn extract_path<T, S>(parts: &mut Parts, state: &S) -> Result<T, AxumErr>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    let Path(value) = Path::<T>::from_request_parts(parts, state)
+        .await
+        .map_err(|err| HttpErr::new(StatusCode::BAD_REQUEST, &err.body_text()))?;
+    Ok(value)
+}
+
+/// A lowercase RFC 1123 label of at most 63 characters, the rule for namespace and container
+/// names.
+pub fn is_dns1123_label(name: &str) -> bool {
+    name.len() <= 63 && is_label_part(name)
+}
+
+/// Dot-separated labels of at most 253 characters, the rule for pod names.
+pub fn is_dns1123_subdomain(name: &str) -> bool {
+    name.len() <= 253 && name.split('.').all(is_label_part)
+}
+
+fn is_label_part(part: &str) -> bool {
+    let alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    part.starts_with(alphanumeric)
+        && part.ends_with(alphanumeric)
+        && part.chars().all(|c| alphanumeric(c) || c == '-')
+}
+
+/// Fails with 400 when `name` breaks the naming rule of a `kind`.
+pub fn validate_name(kind: &str, name: &str, is_valid: fn(&str) -> bool) -> Result<()> {
+    if !is_valid(name) {
+        let message = format!("Invalid {kind} name {name:?}, expected a lowercase RFC 1123 name.");
+        return Err(HttpErr::new(StatusCode::BAD_REQUEST, &message).into());
+    }
+    Ok(())
+}
+
 #[derive(Debug, Default)]
 pub struct PodExecUrl {
     pub domain: String,
@@ -57,8 +288,8 @@ impl PodExecUrl {
             port: String::from(kube_port),
             path: PodExecPath {
                 base_path: String::from("/api/v1"),
-                namespace: format!("/namespaces/{}", coords.namespace),
-                pod: format!("/pods/{}", coords.pod),
+                namespace: coords.namespace.clone(),
+                pod: coords.pod.clone(),
                 tail_path: String::from("/exec"),
             },
         }
@@ -71,6 +302,7 @@ impl PodExecUrl {
     }
 }
 
+/// `namespace` and `pod` are names, they are encoded as path segments when formatted.
 #[derive(Debug, Default)]
 pub struct PodExecPath {
     pub base_path: String,
@@ -83,8 +315,11 @@ impl fmt::Display for PodExecPath {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{}{}{}{}",
-            self.base_path, self.namespace, self.pod, self.tail_path
+            "{}/namespaces/{}/pods/{}{}",
+            self.base_path,
+            encode_query_value(&self.namespace),
+            encode_query_value(&self.pod),
+            self.tail_path
         )
     }
 }
@@ -96,40 +331,135 @@ pub struct PodExecParams {
     pub stdout: bool,
     pub stderr: bool,
     pub tty: bool,
-    pub command: String,
+    /// Program and arguments, one `command` parameter each.
+    pub command: Vec<String>,
     pub pretty: bool,
     pub follow: bool,
 }
 
 impl PodExecParams {
     pub fn format(&self) -> String {
-        format!(
-            "?container={}&stdin={}&stdout={}&stderr={}&tty={}&command={}&pretty={}&follow={}",
-            self.container,
-            self.stdin,
-            self.stdout,
-            self.stderr,
-            self.tty,
-            self.command,
-            self.pretty,
-            self.follow
-        )
+        QueryBuilder::default()
+            .param("container", &self.container)
+            .param("stdin", self.stdin)
+            .param("stdout", self.stdout)
+            .param("stderr", self.stderr)
+            .param("tty", self.tty)
+            .params("command", &self.command)
+            .param("pretty", self.pretty)
+            .param("follow", self.follow)
+            .build()
     }
-    pub fn get_pod_exec_params(&self, coords: &ContainerCoords) -> Self {
+    /// Interactive shell, marked with `session_id` in its environment when part of a session.
+    pub fn get_pod_exec_params(
+        &self,
+        coords: &ContainerCoords,
+        options: &TerminalOptions,
+        session_id: Option<&str>,
+    ) -> Self {
         Self {
             container: coords.container.clone(),
             stdin: true,
             stdout: true,
             stderr: true,
             tty: true,
-            command: "env&env=TERM%3Dxterm&command=COLUMNS%3D800&command=LINES%3D10&command=bash"
-                .to_string(),
+            command: options.command(session_id),
             pretty: true,
             follow: true,
         }
     }
 }
 
+impl PodExecParams {
+    /// Non-interactive exec of `command`: no stdin, no TTY, stdout and stderr kept apart.
+    pub fn get_pod_command_params(&self, container: &str, command: &[String]) -> Self {
+        Self {
+            container: container.to_string(),
+            stdin: false,
+            stdout: true,
+            stderr: true,
+            tty: false,
+            command: command.to_vec(),
+            pretty: true,
+            follow: false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ExecOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// `None` when the process didn't report an exit code, see `error`.
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+    /// Set when stdout or stderr were cut at the output limit.
+    pub truncated: bool,
+}
+
+/// Runs `command` to completion in the container and collects its output, keeping at most
+/// `output_limit` bytes of stdout and of stderr.
+pub async fn exec_command(
+    sat: &ServiceAccountToken,
+    coords: &ContainerCoords,
+    command: &[String],
+    output_limit: usize,
+) -> Result<ExecOutput, anyhow::Error> {
+    let pod_exec_url = PodExecUrl::default().get_exec_url(&sat.kube_host, &sat.kube_port, coords);
+    let pod_exec_params =
+        PodExecParams::default().get_pod_command_params(&coords.container, command);
+    let mut kube_ws_stream = pod_exec_connector(sat, &pod_exec_url, &pod_exec_params).await?;
+
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut output = ExecOutput::default();
+    while let Some(msg) = kube_ws_stream.next().await {
+        let data = match msg? {
+            Message::Binary(data) if !data.is_empty() => data,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let buffer = match data[0] {
+            STD_OUTPUT_PREFIX_NORMAL => &mut stdout,
+            STD_OUTPUT_PREFIX_ERR => &mut stderr,
+            STATUS_PREFIX => {
+                let status: Status = serde_json::from_slice(&data[1..])?;
+                output.exit_code = exit_code_from_status(&status);
+                if output.exit_code.is_none() {
+                    output.error = status.message;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        let room = output_limit.saturating_sub(buffer.len());
+        output.truncated |= data.len() - 1 > room;
+        buffer.extend_from_slice(&data[1..][..room.min(data.len() - 1)]);
+    }
+
+    output.stdout = String::from_utf8_lossy(&stdout).into_owned();
+    output.stderr = String::from_utf8_lossy(&stderr).into_owned();
+    Ok(output)
+}
+
+/// Reads the exit code from the status the API server sends on the error channel.
+pub fn exit_code_from_status(status: &Status) -> Option<i32> {
+    match status.status.as_deref() {
+        Some("Success") => Some(0),
+        _ => status
+            .details
+            .as_ref()?
+            .causes
+            .iter()
+            .flatten()
+            .find(|cause| cause.reason.as_deref() == Some("ExitCode"))?
+            .message
+            .as_deref()?
+            .parse()
+            .ok(),
+    }
+}
+
 pub async fn pod_exec_connector(
     sat: &ServiceAccountToken,
     pod_exec_url: &PodExecUrl,
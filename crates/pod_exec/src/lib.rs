@@ -1,40 +1,299 @@
+pub mod audit;
+pub mod auth;
+pub mod broadcast;
 pub mod connector;
+pub mod limits;
 pub mod model;
 pub mod msg_handle;
+pub mod multi_pane;
+pub mod pipeline;
+pub mod pod_detail;
+pub mod protocol;
+pub mod selector;
 pub mod services;
+pub mod session;
+pub mod signal;
+pub mod workload;
 
+use auth::{Groups, User};
 use axum::{extract::WebSocketUpgrade, response::Response};
+use broadcast::broadcast_exec;
 use common::{
     axum::{
         self,
-        extract::{Query, RawPathParams},
+        body::Body,
+        extract::{Path, Query},
+        http::{header, StatusCode},
         response::IntoResponse,
-        Extension,
+        Extension, Json,
     },
-    tracing,
+    futures_util::StreamExt as _,
+    serde_json, tracing,
 };
-use connector::ContainerCoords;
+use connector::{ContainerCoords, NamespaceCoords, TerminalOptions, DEFAULT_SHELL};
 use context::context::Context;
-use model::ContainerQuery;
-use services::{get_container_list, get_ns_list, handle_socket};
-use std::borrow::Cow;
-use util::{err::AxumErr, rsp::Rsp};
+use model::{
+    BroadcastReq, ContainerQuery, DebugQuery, MultiPaneQuery, NamespaceQuery, ResumeQuery,
+    TerminalQuery, WatchQuery, WorkloadCoords, WorkloadQuery,
+};
+use multi_pane::{handle_multi_pane_socket, resolve_panes};
+use pipeline::{TERMINAL_PROTOCOLS, TEXT_PROTOCOL};
+use pod_detail::get_pod_detail;
+use protocol::Signal;
+use services::{
+    create_debug_container, default_container, get_container_list, get_ns_list, get_pod,
+    handle_join_socket, handle_resume_socket, handle_socket, handle_watch_socket,
+    pod_container_list, require_container,
+};
+use session::SessionRegistry;
+use signal::signal_session;
+use util::{
+    err::{AxumErr, HttpErr},
+    rsp::Rsp,
+};
+use workload::{get_workload_list, get_workload_pods, pick_ready_pod};
+
+pub async fn handler(
+    ws: WebSocketUpgrade,
+    coords: ContainerCoords,
+    Query(terminal): Query<TerminalQuery>,
+    user: User,
+    Extension(sessions): Extension<SessionRegistry>,
+    Extension(ctx): Extension<Context>,
+) -> Result<Response, AxumErr> {
+    let options = TerminalOptions::new(DEFAULT_SHELL, &terminal, &ctx.config)?;
+    tracing::info!("{:?}", coords);
+    let permit = sessions.acquire(&user, &coords)?;
+    let pod = get_pod(&ctx, &coords.namespace, &coords.pod).await?;
+    require_container(&pod, &coords.container)?;
 
-pub async fn handler(ws: WebSocketUpgrade, raw_path_params: RawPathParams) -> Response {
-    let coords = ContainerCoords::default().populate_from_raw_path_params(&raw_path_params);
+    Ok(ws.protocols(TERMINAL_PROTOCOLS).on_upgrade(|axum_socket| {
+        handle_socket(axum_socket, coords, options, user, permit, sessions, ctx)
+    }))
+}
+
+/// Opens a terminal on the pod's default container, picked the way `kubectl exec` does.
+pub async fn default_container_handler(
+    ws: WebSocketUpgrade,
+    mut coords: ContainerCoords,
+    Query(terminal): Query<TerminalQuery>,
+    user: User,
+    Extension(sessions): Extension<SessionRegistry>,
+    Extension(ctx): Extension<Context>,
+) -> Result<Response, AxumErr> {
+    let options = TerminalOptions::new(DEFAULT_SHELL, &terminal, &ctx.config)?;
+    let pod = get_pod(&ctx, &coords.namespace, &coords.pod).await?;
+    coords.container = default_container(&pod)?;
     tracing::info!("{:?}", coords);
+    let permit = sessions.acquire(&user, &coords)?;
+
+    Ok(ws.protocols(TERMINAL_PROTOCOLS).on_upgrade(|axum_socket| {
+        handle_socket(axum_socket, coords, options, user, permit, sessions, ctx)
+    }))
+}
+
+pub async fn debug_handler(
+    ws: WebSocketUpgrade,
+    mut coords: ContainerCoords,
+    Query(req): Query<DebugQuery>,
+    Query(terminal): Query<TerminalQuery>,
+    user: User,
+    Extension(sessions): Extension<SessionRegistry>,
+    Extension(ctx): Extension<Context>,
+) -> Result<Response, AxumErr> {
+    let shell = req.shell.clone().unwrap_or(ctx.config.debug_shell.clone());
+    let options = TerminalOptions::new(&shell, &terminal, &ctx.config)?;
+    let permit = sessions.acquire(&user, &coords)?;
+    coords.container = create_debug_container(&ctx, &coords, req).await?;
+    tracing::info!("{:?}", coords);
+
+    Ok(ws.protocols(TERMINAL_PROTOCOLS).on_upgrade(|axum_socket| {
+        handle_socket(axum_socket, coords, options, user, permit, sessions, ctx)
+    }))
+}
+
+/// Opens a terminal on the newest ready pod of a deployment, statefulset, daemonset or job.
+pub async fn workload_handler(
+    ws: WebSocketUpgrade,
+    workload: WorkloadCoords,
+    Query(terminal): Query<TerminalQuery>,
+    user: User,
+    Extension(sessions): Extension<SessionRegistry>,
+    Extension(ctx): Extension<Context>,
+) -> Result<Response, AxumErr> {
+    let options = TerminalOptions::new(DEFAULT_SHELL, &terminal, &ctx.config)?;
+    let pod = pick_ready_pod(&ctx, &workload).await?;
+    require_container(&pod, &workload.container)?;
+    let coords = ContainerCoords {
+        pod: pod.metadata.name.unwrap_or_default(),
+        namespace: workload.namespace,
+        container: workload.container,
+    };
+    tracing::info!("{:?}", coords);
+    let permit = sessions.acquire(&user, &coords)?;
+
+    Ok(ws.protocols(TERMINAL_PROTOCOLS).on_upgrade(|axum_socket| {
+        handle_socket(axum_socket, coords, options, user, permit, sessions, ctx)
+    }))
+}
+
+/// Opens one terminal per selected pod, every keystroke is sent to all of them.
+pub async fn multi_pane_handler(
+    ws: WebSocketUpgrade,
+    NamespaceCoords { namespace }: NamespaceCoords,
+    Query(req): Query<MultiPaneQuery>,
+    Query(terminal): Query<TerminalQuery>,
+    user: User,
+    Extension(sessions): Extension<SessionRegistry>,
+    Extension(ctx): Extension<Context>,
+) -> Result<Response, AxumErr> {
+    req.validate()?;
+    let options = TerminalOptions::new(
+        req.shell.as_deref().unwrap_or(DEFAULT_SHELL),
+        &terminal,
+        &ctx.config,
+    )?;
+    let panes = resolve_panes(&ctx, &namespace, &req).await?;
+    let permits = panes
+        .iter()
+        .map(|coords| sessions.acquire(&user, coords))
+        .collect::<Result<Vec<_>, _>>()?;
+    tracing::info!("Multi-pane session on {} pods", panes.len());
 
-    let protocols: Vec<Cow<'static, str>> = vec![Cow::Borrowed("echo-protocol")];
-    ws.protocols(protocols)
-        .on_upgrade(|axum_socket| handle_socket(axum_socket, coords))
+    // Pane output is tagged JSON, only the text protocol applies.
+    Ok(ws.protocols([TEXT_PROTOCOL]).on_upgrade(|axum_socket| {
+        handle_multi_pane_socket(axum_socket, panes, permits, options, user, sessions, ctx)
+    }))
+}
+
+/// Joins a session shared by its owner, output is mirrored and input needs a driver grant.
+pub async fn session_join_handler(
+    ws: WebSocketUpgrade,
+    Path(id): Path<String>,
+    user: User,
+    Extension(sessions): Extension<SessionRegistry>,
+) -> Result<Response, AxumErr> {
+    user.require_authenticated()?;
+    let session = sessions.get(&id)?;
+    if session.owner == auth::ANONYMOUS {
+        let message = "Sessions of anonymous users can't be joined.";
+        return Err(HttpErr::new(StatusCode::FORBIDDEN, message).into());
+    }
+    // Viewers count against the limits like terminals of their own.
+    let permit = sessions.acquire(&user, &session.coords)?;
+    tracing::info!("{} joins session {} of {}", user.0, id, session.owner);
+
+    Ok(ws
+        .protocols(TERMINAL_PROTOCOLS)
+        .on_upgrade(|axum_socket| async move {
+            handle_join_socket(axum_socket, session, user).await;
+            drop(permit);
+        }))
+}
+
+/// Resumes a session whose owner lost the connection, with the token handed out on creation.
+pub async fn session_resume_handler(
+    ws: WebSocketUpgrade,
+    Path(id): Path<String>,
+    Query(req): Query<ResumeQuery>,
+    user: User,
+    Extension(sessions): Extension<SessionRegistry>,
+    Extension(ctx): Extension<Context>,
+) -> Result<Response, AxumErr> {
+    user.require_authenticated()?;
+    let session = sessions.get_for_resume(&id, &req.token, &user)?;
+    tracing::info!("{} resumes session {}", user.0, id);
+
+    Ok(ws
+        .protocols(TERMINAL_PROTOCOLS)
+        .on_upgrade(|axum_socket| handle_resume_socket(axum_socket, session, user, sessions, ctx)))
+}
+
+/// Owner, drivers and attached viewers only, the info lists who else is in the session.
+pub async fn session_info(
+    Path(id): Path<String>,
+    user: User,
+    Extension(sessions): Extension<SessionRegistry>,
+) -> Result<impl IntoResponse, AxumErr> {
+    user.require_authenticated()?;
+    let session = sessions.get(&id)?;
+    if !session.is_participant(&user) {
+        let message = "Only participants of the session can see it.";
+        return Err(HttpErr::new(StatusCode::FORBIDDEN, message).into());
+    }
+
+    Ok(Rsp::success_with_data(
+        session.info(),
+        "Data fetched successfully.",
+    ))
+}
+
+/// Lets `driver` send input to the session, owner only.
+pub async fn session_driver_grant(
+    Path((id, driver)): Path<(String, String)>,
+    user: User,
+    Extension(sessions): Extension<SessionRegistry>,
+) -> Result<impl IntoResponse, AxumErr> {
+    user.require_authenticated()?;
+    let session_info = sessions.set_driver(&id, &user, &driver, true)?;
+
+    Ok(Rsp::success_with_data(session_info, "Driver granted."))
+}
+
+pub async fn session_driver_revoke(
+    Path((id, driver)): Path<(String, String)>,
+    user: User,
+    Extension(sessions): Extension<SessionRegistry>,
+) -> Result<impl IntoResponse, AxumErr> {
+    user.require_authenticated()?;
+    let session_info = sessions.set_driver(&id, &user, &driver, false)?;
+
+    Ok(Rsp::success_with_data(session_info, "Driver revoked."))
+}
+
+/// Sends a signal to the foreground process of the session, e.g. for a Ctrl-C button. Owner and
+/// drivers only.
+pub async fn session_signal(
+    Path((id, signal)): Path<(String, Signal)>,
+    user: User,
+    Extension(sessions): Extension<SessionRegistry>,
+) -> Result<impl IntoResponse, AxumErr> {
+    user.require_authenticated()?;
+    let session = sessions.get(&id)?;
+    signal_session(&session, &user, signal).await?;
+
+    Ok(Rsp::success_with_data(
+        session.info(),
+        &format!("SIG{} sent.", signal.kill_name()),
+    ))
+}
+
+pub async fn watch_handler(
+    ws: WebSocketUpgrade,
+    Query(req): Query<WatchQuery>,
+    user: User,
+    Extension(sessions): Extension<SessionRegistry>,
+    Extension(ctx): Extension<Context>,
+) -> Result<Response, AxumErr> {
+    req.validate()?;
+    let permit = sessions.acquire_stream(&user)?;
+    tracing::info!("Watch pods {:?}", req);
+
+    Ok(ws.on_upgrade(|axum_socket| async move {
+        handle_watch_socket(axum_socket, req, ctx).await;
+        drop(permit);
+    }))
 }
 
 pub async fn container_list(
     Query(req): Query<ContainerQuery>,
+    user: User,
+    groups: Groups,
     Extension(ctx): Extension<Context>,
 ) -> Result<impl IntoResponse, AxumErr> {
     tracing::info!("Get container list");
-    let container_res = get_container_list(req, ctx).await?;
+    req.validate()?;
+    let container_res = get_container_list(req, &user, &groups, ctx).await?;
 
     Ok(Rsp::success_with_optional_biz_status(
         container_res,
@@ -43,12 +302,117 @@ pub async fn container_list(
     ))
 }
 
-pub async fn ns_list(Extension(ctx): Extension<Context>) -> Result<impl IntoResponse, AxumErr> {
+/// Readiness probe, fails until the cache (when enabled) has completed its initial sync.
+pub async fn ready(Extension(ctx): Extension<Context>) -> Response {
+    match &ctx.cache {
+        Some(cache) if !cache.is_ready() => Rsp::<()>::error(503, "Cache is still syncing.")
+            .with_http_status(StatusCode::SERVICE_UNAVAILABLE)
+            .into_response(),
+        _ => Rsp::<()>::success_without_data("Ready.").into_response(),
+    }
+}
+
+/// Prometheus text exposition of the cache state.
+pub async fn metrics(Extension(ctx): Extension<Context>) -> String {
+    let mut metrics = String::new();
+    if let Some(cache) = &ctx.cache {
+        metrics
+            .push_str("# HELP kube_term_cache_ready Whether the initial cache sync completed.\n");
+        metrics.push_str("# TYPE kube_term_cache_ready gauge\n");
+        metrics.push_str(&format!(
+            "kube_term_cache_ready {}\n",
+            u8::from(cache.is_ready())
+        ));
+        metrics.push_str(
+            "# HELP kube_term_cache_staleness_seconds Seconds since the last watch event, once synced.\n",
+        );
+        metrics.push_str("# TYPE kube_term_cache_staleness_seconds gauge\n");
+        for (resource, staleness) in cache.staleness_secs() {
+            metrics.push_str(&format!(
+                "kube_term_cache_staleness_seconds{{resource=\"{resource}\"}} {staleness:.3}\n"
+            ));
+        }
+    }
+    metrics
+}
+
+pub async fn ns_list(
+    Query(req): Query<NamespaceQuery>,
+    user: User,
+    groups: Groups,
+    Extension(ctx): Extension<Context>,
+) -> Result<impl IntoResponse, AxumErr> {
     tracing::info!("Get namespace list");
-    let namespace_list = get_ns_list(ctx).await?;
+    let namespace_list = get_ns_list(req, &user, &groups, ctx).await?;
 
     Ok(Rsp::success_with_data(
         namespace_list,
         "Data fetched successfully.",
     ))
 }
+
+pub async fn workload_list(
+    NamespaceCoords { namespace }: NamespaceCoords,
+    Query(req): Query<WorkloadQuery>,
+    Extension(ctx): Extension<Context>,
+) -> Result<impl IntoResponse, AxumErr> {
+    tracing::info!("Get workload list");
+    let workload_list = get_workload_list(&namespace, req, ctx).await?;
+
+    Ok(Rsp::success_with_data(
+        workload_list,
+        "Data fetched successfully.",
+    ))
+}
+
+pub async fn workload_pod_list(
+    workload: WorkloadCoords,
+    Extension(ctx): Extension<Context>,
+) -> Result<impl IntoResponse, AxumErr> {
+    tracing::info!("Get pods of {} {}", workload.kind.as_str(), workload.name);
+    let pods = get_workload_pods(&ctx, &workload.namespace, workload.kind, &workload.name).await?;
+    let container_list: Vec<_> = pods.iter().flat_map(pod_container_list).collect();
+
+    Ok(Rsp::success_with_data(
+        container_list,
+        "Data fetched successfully.",
+    ))
+}
+
+pub async fn pod_detail(
+    coords: ContainerCoords,
+    Extension(ctx): Extension<Context>,
+) -> Result<impl IntoResponse, AxumErr> {
+    tracing::info!("Get pod detail {}/{}", coords.namespace, coords.pod);
+    let pod_detail = get_pod_detail(&ctx, &coords.namespace, &coords.pod).await?;
+
+    Ok(Rsp::success_with_data(
+        pod_detail,
+        "Data fetched successfully.",
+    ))
+}
+
+/// Streams one NDJSON line per pod as the command completes there, then a summary line.
+pub async fn broadcast(
+    NamespaceCoords { namespace }: NamespaceCoords,
+    user: User,
+    Extension(sessions): Extension<SessionRegistry>,
+    Extension(ctx): Extension<Context>,
+    Json(req): Json<BroadcastReq>,
+) -> Result<impl IntoResponse, AxumErr> {
+    let permit = sessions.acquire_stream(&user)?;
+    let events = broadcast_exec(&ctx, &namespace, req).await?;
+    // The slot is held until the last line is streamed.
+    let lines = events.map(move |event| {
+        let _permit = &permit;
+        serde_json::to_string(&event).map(|mut line| {
+            line.push('\n');
+            line
+        })
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    ))
+}
@@ -0,0 +1,214 @@
+use common::{
+    anyhow,
+    axum::{
+        extract::Request,
+        http::{header, HeaderMap, HeaderValue, StatusCode},
+        middleware::Next,
+        response::Response,
+        Extension,
+    },
+};
+use context::config::AppConfig;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use util::err::{AxumErr, HttpErr};
+
+use crate::{auth::User, connector::ContainerCoords};
+
+/// Buckets kept before full (idle) ones are dropped.
+const RATE_LIMIT_MAX_KEYS: usize = 10_000;
+
+#[derive(Debug, Default)]
+struct SessionCounts {
+    total: usize,
+    users: HashMap<String, usize>,
+    pods: HashMap<String, usize>,
+}
+
+/// Caps concurrent sessions per user, per target pod and overall. A limit of 0 means
+/// unlimited.
+#[derive(Debug)]
+pub struct SessionLimiter {
+    per_user: usize,
+    per_pod: usize,
+    global: usize,
+    counts: Mutex<SessionCounts>,
+}
+
+impl SessionLimiter {
+    pub fn new(config: &AppConfig) -> Self {
+        Self {
+            per_user: config.max_sessions_per_user,
+            per_pod: config.max_sessions_per_pod,
+            global: config.max_sessions,
+            counts: Default::default(),
+        }
+    }
+
+    /// Takes a slot for `user` on the pod, released when the permit is dropped. Fails with
+    /// 429 when any of the limits is reached.
+    pub fn acquire(
+        self: &Arc<Self>,
+        user: &User,
+        coords: &ContainerCoords,
+    ) -> Result<SessionPermit, anyhow::Error> {
+        let pod = format!("{}/{}", coords.namespace, coords.pod);
+        self.take(user, Some(pod))
+    }
+
+    /// Takes a slot for a stream that isn't bound to one pod, such as a pod watch or a
+    /// broadcast, only counted against the per-user and global limits.
+    pub fn acquire_stream(self: &Arc<Self>, user: &User) -> Result<SessionPermit, anyhow::Error> {
+        self.take(user, None)
+    }
+
+    fn take(
+        self: &Arc<Self>,
+        user: &User,
+        pod: Option<String>,
+    ) -> Result<SessionPermit, anyhow::Error> {
+        let mut counts = self.counts.lock().unwrap();
+        let exceeded = |limit: usize, count: usize| limit > 0 && count >= limit;
+
+        let message = if exceeded(self.global, counts.total) {
+            Some("Too many open sessions, try again later.".to_string())
+        } else if exceeded(self.per_user, *counts.users.get(&user.0).unwrap_or(&0)) {
+            Some(format!(
+                "{} already has {} open sessions.",
+                user.0, self.per_user
+            ))
+        } else {
+            let pod_count = |pod: &String| *counts.pods.get(pod).unwrap_or(&0);
+            pod.as_ref()
+                .filter(|pod| exceeded(self.per_pod, pod_count(pod)))
+                .map(|pod| format!("Pod {pod} already has {} open sessions.", self.per_pod))
+        };
+        if let Some(message) = message {
+            return Err(HttpErr::new(StatusCode::TOO_MANY_REQUESTS, &message).into());
+        }
+
+        counts.total += 1;
+        *counts.users.entry(user.0.clone()).or_default() += 1;
+        if let Some(pod) = &pod {
+            *counts.pods.entry(pod.clone()).or_default() += 1;
+        }
+        Ok(SessionPermit {
+            limiter: self.clone(),
+            user: user.0.clone(),
+            pod,
+        })
+    }
+
+    fn release(&self, user: &str, pod: Option<&str>) {
+        let mut counts = self.counts.lock().unwrap();
+        let counts = &mut *counts;
+        counts.total = counts.total.saturating_sub(1);
+        for (map, key) in [(&mut counts.users, Some(user)), (&mut counts.pods, pod)] {
+            let Some(key) = key else {
+                continue;
+            };
+            if let Some(count) = map.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    map.remove(key);
+                }
+            }
+        }
+    }
+
+    pub fn open_sessions(&self) -> usize {
+        self.counts.lock().unwrap().total
+    }
+}
+
+/// A session slot, see `SessionLimiter::acquire` and `SessionLimiter::acquire_stream`.
+#[derive(Debug)]
+pub struct SessionPermit {
+    limiter: Arc<SessionLimiter>,
+    user: String,
+    pod: Option<String>,
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.user, self.pod.as_deref());
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per user: `burst` requests at once, refilled at `rate` per second.
+#[derive(Clone)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1.0),
+            buckets: Default::default(),
+        }
+    }
+
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self::new(config.list_rate_per_sec, config.list_rate_burst)
+    }
+
+    /// Takes a token for `key`, or returns the seconds until one is available.
+    pub fn check(&self, key: &str) -> Result<(), u64> {
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= RATE_LIMIT_MAX_KEYS {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.rate).ceil() as u64)
+        }
+    }
+}
+
+/// Middleware for the list endpoints, answers 429 with `Retry-After` once the caller's
+/// bucket is empty. Anonymous callers share one bucket.
+pub async fn rate_limit(
+    user: User,
+    Extension(limiter): Extension<RateLimiter>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AxumErr> {
+    if let Err(retry_after) = limiter.check(&user.0) {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, HeaderValue::from(retry_after.max(1)));
+        let message = format!("Too many requests, retry in {}s.", retry_after.max(1));
+        let err = HttpErr::new(StatusCode::TOO_MANY_REQUESTS, &message).with_headers(headers);
+        return Err(err.into());
+    }
+    Ok(next.run(req).await)
+}
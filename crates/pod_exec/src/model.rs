@@ -1,12 +1,292 @@
-use crate::connector::ContainerCoordsOptional;
-use serde::{Deserialize, Serialize};
+use crate::{
+    connector::{
+        extract_path, is_dns1123_label, is_dns1123_subdomain, validate_name,
+        ContainerCoordsOptional,
+    },
+    pipeline::Frame,
+    protocol::TermMessage,
+    workload::label_selector_string,
+};
+use common::{
+    anyhow,
+    axum::{
+        async_trait,
+        extract::FromRequestParts,
+        http::{request::Parts, StatusCode},
+    },
+    base64, serde_json,
+};
+use kube::k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, ObjectMeta, Time};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+use util::err::{AxumErr, HttpErr};
 
 #[derive(Default, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ContainerQuery {
+    /// Comma-separated namespaces, e.g. `default,payments`. Defaults to `default`.
     pub ns: Option<String>,
-    pub page_size: Option<i8>,
+    /// Lists pods across every namespace, `ns` is ignored. Callers who may not list pods
+    /// cluster-wide get the namespaces they may list pods in.
+    pub all_namespaces: Option<bool>,
+    /// Number of pods per page, every container of a pod is returned on the same page. With
+    /// `search` or `image`, pods are read until that many containers match.
+    pub page_size: Option<u32>,
     pub page_token: Option<String>,
+    /// Passed through to the API server, e.g. `app=payments,tier!=cache`.
+    pub label_selector: Option<String>,
+    /// Passed through to the API server, e.g. `status.phase=Running,spec.nodeName=node-1`.
+    pub field_selector: Option<String>,
+    /// Case-insensitive substring matched against pod and container names.
+    pub search: Option<String>,
+    /// Case-insensitive substring matched against the container image.
+    pub image: Option<String>,
+}
+
+impl ContainerQuery {
+    pub fn namespaces(&self) -> Vec<String> {
+        split_namespaces(self.ns.as_deref())
+    }
+
+    /// Fails with 400 when one of the namespaces isn't a valid namespace name.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        validate_namespaces(&self.namespaces())
+    }
+
+    /// Whether `matches` can drop containers, then a page of pods may not fill a page.
+    pub fn filters_containers(&self) -> bool {
+        self.search.is_some() || self.image.is_some()
+    }
+
+    /// Applies the filters the API server can't evaluate for us.
+    pub fn matches(&self, info: &ContainerSimpleInfo) -> bool {
+        let contains = |haystack: Option<&str>, needle: &str| {
+            haystack.is_some_and(|h| h.to_lowercase().contains(&needle.to_lowercase()))
+        };
+
+        let search_matched = self.search.as_deref().is_none_or(|search| {
+            contains(info.container.pod.as_deref(), search)
+                || contains(info.container.container.as_deref(), search)
+        });
+        let image_matched = self
+            .image
+            .as_deref()
+            .is_none_or(|image| contains(Some(&info.container_image), image));
+
+        search_matched && image_matched
+    }
+}
+
+fn validate_namespaces(namespaces: &[String]) -> Result<(), anyhow::Error> {
+    namespaces
+        .iter()
+        .try_for_each(|ns| validate_name("namespace", ns, is_dns1123_label))
+}
+
+/// Splits a comma-separated namespace list, falling back to `default`.
+pub fn split_namespaces(ns: Option<&str>) -> Vec<String> {
+    let namespaces: Vec<String> = ns
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|ns| !ns.is_empty())
+        .map(str::to_string)
+        .collect();
+    if namespaces.is_empty() {
+        vec!["default".to_string()]
+    } else {
+        namespaces
+    }
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchQuery {
+    /// Comma-separated namespaces, e.g. `default,payments`. Defaults to `default`.
+    pub ns: Option<String>,
+    /// Watches pods across every namespace, `ns` is ignored.
+    pub all_namespaces: Option<bool>,
+    pub label_selector: Option<String>,
+    pub field_selector: Option<String>,
+}
+
+impl WatchQuery {
+    pub fn namespaces(&self) -> Vec<String> {
+        split_namespaces(self.ns.as_deref())
+    }
+
+    /// Fails with 400 when one of the namespaces isn't a valid namespace name.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        validate_namespaces(&self.namespaces())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PodWatchEventType {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// Pushed over `/watch` for every pod change, carrying the same rows as `/container`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodWatchEvent {
+    pub r#type: PodWatchEventType,
+    pub namespace: String,
+    pub pod: String,
+    /// Set once the pod has been asked to terminate.
+    pub terminating: bool,
+    pub container_list: Vec<ContainerSimpleInfo>,
+}
+
+/// Page token handed out when listing several namespaces: the namespace currently being paged
+/// through and the API server continue token within it.
+#[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NamespaceCursor {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl NamespaceCursor {
+    pub fn encode(&self) -> String {
+        encode_page_token(self)
+    }
+
+    pub fn decode(page_token: &str) -> Result<Self, anyhow::Error> {
+        decode_page_token(page_token)
+    }
+}
+
+/// Page token handed out when the list is served from the cache: the offset into the pods
+/// sorted by namespace and name.
+#[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheCursor {
+    pub offset: usize,
+}
+
+impl CacheCursor {
+    pub fn encode(&self) -> String {
+        encode_page_token(self)
+    }
+
+    pub fn decode(page_token: &str) -> Result<Self, anyhow::Error> {
+        decode_page_token(page_token)
+    }
+}
+
+fn encode_page_token<T: Serialize>(cursor: &T) -> String {
+    let cursor = serde_json::to_vec(cursor).unwrap_or_default();
+    base64::Engine::encode(&base64::prelude::BASE64_URL_SAFE_NO_PAD, cursor)
+}
+
+fn decode_page_token<T: DeserializeOwned>(page_token: &str) -> Result<T, anyhow::Error> {
+    let cursor = base64::Engine::decode(&base64::prelude::BASE64_URL_SAFE_NO_PAD, page_token)?;
+    Ok(serde_json::from_slice(&cursor)?)
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DebugQuery {
+    pub image: Option<String>,
+    pub target_container_name: Option<String>,
+    pub shell: Option<String>,
+}
+
+/// Terminal settings on the upgrade request, e.g. `?rows=24&cols=80&term=xterm-256color`.
+/// The size is applied as soon as the exec stream is open, before the first resize from the
+/// client. The rest falls back to the `session_*` config defaults.
+#[derive(Default, Debug, Clone, Deserialize)]
+pub struct TerminalQuery {
+    pub rows: Option<u16>,
+    pub cols: Option<u16>,
+    pub term: Option<String>,
+    pub lang: Option<String>,
+    pub workdir: Option<String>,
+    /// Comma-separated `KEY=VALUE` pairs, set after the config defaults.
+    pub env: Option<String>,
+}
+
+impl TerminalQuery {
+    /// The resize frame for the exec stream, `None` without a size. Both dimensions are
+    /// required and can't be 0.
+    pub fn resize_frame(&self) -> Result<Option<Frame>, anyhow::Error> {
+        match (self.rows, self.cols) {
+            (None, None) => Ok(None),
+            (Some(rows), Some(cols)) if rows > 0 && cols > 0 => {
+                Ok(TermMessage::Resize { rows, cols }.to_kube_frame())
+            }
+            _ => {
+                let message = "rows and cols must both be given and greater than 0.";
+                Err(HttpErr::new(StatusCode::BAD_REQUEST, message).into())
+            }
+        }
+    }
+}
+
+/// Selects the panes of a synchronized multi-pane session, either by name or by label.
+#[derive(Default, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MultiPaneQuery {
+    /// Comma-separated pod names, e.g. `web-0,web-1`.
+    pub pods: Option<String>,
+    /// Used when `pods` is empty, only running pods are selected.
+    pub label_selector: Option<String>,
+    /// Defaults to the first container of each pod.
+    pub container: Option<String>,
+    pub shell: Option<String>,
+}
+
+impl MultiPaneQuery {
+    pub fn pod_names(&self) -> Vec<&str> {
+        self.pods
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+
+    /// Fails with 400 on a pod or container name Kubernetes wouldn't accept.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        for pod in self.pod_names() {
+            validate_name("pod", pod, is_dns1123_subdomain)?;
+        }
+        if let Some(container) = &self.container {
+            validate_name("container", container, is_dns1123_label)?;
+        }
+        Ok(())
+    }
+}
+
+/// Output of one pane, `data` is the same frame a single-pod terminal receives.
+#[derive(Debug, Serialize)]
+pub struct PaneFrame {
+    pub pod: String,
+    pub data: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResumeQuery {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    pub id: String,
+    pub owner: String,
+    pub namespace: String,
+    pub pod: String,
+    pub container: String,
+    /// Users besides the owner allowed to send input.
+    pub drivers: Vec<String>,
+    /// Currently attached users, a user attached twice is listed twice.
+    pub participants: Vec<String>,
 }
 
 #[derive(Default, Debug, Serialize)]
@@ -14,17 +294,66 @@ pub struct ContainerQuery {
 pub struct ContainerSimpleInfo {
     #[serde(flatten)]
     pub container: ContainerCoordsOptional,
+    pub container_kind: ContainerKind,
     pub pod_ip: String,
     pub pod_phase: String,
+    pub node_name: String,
     pub container_image: String,
+    pub container_state: ContainerStateInfo,
+    pub ready: bool,
+    pub restart_count: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<Time>,
+    /// Whether a terminal session can currently be opened against the container.
+    pub executable: bool,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ContainerKind {
+    Init,
+    #[default]
+    Container,
+    Ephemeral,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ContainerStateKind {
+    Running,
+    Waiting,
+    Terminated,
+    #[default]
+    Unknown,
+}
+
+#[derive(Default, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerStateInfo {
+    pub state: ContainerStateKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
 }
 
 #[derive(Default, Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ContainerRsp {
     pub container_list: Vec<ContainerSimpleInfo>,
+    /// Pods on this page, `container_list` holds their containers.
+    pub pod_count: usize,
+    /// Estimate of the pods left after this page, absent when the API server can't tell
+    /// (e.g. with label or field selectors).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_pod_count: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub page_token: Option<String>,
+    /// Namespaces skipped because the caller, or the service account, may not list pods in them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub denied_namespaces: Vec<String>,
 }
 
 #[derive(Default, Debug, Serialize)]
@@ -34,4 +363,285 @@ pub struct NamespaceSimpleInfo {
     pub name: String,
     pub resource_version: String,
     pub r#type: String,
+    /// `Active` or `Terminating`.
+    pub phase: String,
+    pub labels: BTreeMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creation_timestamp: Option<Time>,
+    /// Only filled in when `withPodCount` is requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pod_count: Option<usize>,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NamespaceQuery {
+    /// Passed through to the API server, e.g. `team=payments`.
+    pub label_selector: Option<String>,
+    /// Case-insensitive substring matched against the namespace name.
+    pub search: Option<String>,
+    /// Only namespaces where the caller may create `pods/exec`, needs an authenticated user.
+    pub exec_only: Option<bool>,
+    pub with_pod_count: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkloadKind {
+    Deployment,
+    StatefulSet,
+    DaemonSet,
+    Job,
+}
+
+impl WorkloadKind {
+    pub const ALL: [WorkloadKind; 4] = [
+        WorkloadKind::Deployment,
+        WorkloadKind::StatefulSet,
+        WorkloadKind::DaemonSet,
+        WorkloadKind::Job,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkloadKind::Deployment => "deployment",
+            WorkloadKind::StatefulSet => "statefulset",
+            WorkloadKind::DaemonSet => "daemonset",
+            WorkloadKind::Job => "job",
+        }
+    }
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkloadQuery {
+    pub kind: Option<WorkloadKind>,
+}
+
+/// Path of `/namespace/:namespace/:kind/:name/container/:container`, extracted and validated
+/// like `ContainerCoords`.
+#[derive(Debug, Deserialize)]
+pub struct WorkloadCoords {
+    pub namespace: String,
+    pub kind: WorkloadKind,
+    pub name: String,
+    /// Missing on the workload pod list route.
+    #[serde(default)]
+    pub container: String,
+}
+
+impl WorkloadCoords {
+    /// Fails with 400 on a name Kubernetes wouldn't accept, like `ContainerCoords::validate`.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        validate_name("namespace", &self.namespace, is_dns1123_label)?;
+        validate_name(self.kind.as_str(), &self.name, is_dns1123_subdomain)?;
+        if !self.container.is_empty() {
+            validate_name("container", &self.container, is_dns1123_label)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for WorkloadCoords
+where
+    S: Send + Sync,
+{
+    type Rejection = AxumErr;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let coords: WorkloadCoords = extract_path(parts, state).await?;
+        coords.validate()?;
+        Ok(coords)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkloadSimpleInfo {
+    pub kind: WorkloadKind,
+    pub namespace: String,
+    pub name: String,
+    pub replicas: i32,
+    pub ready_replicas: i32,
+    /// Label selector of the workload's pods, e.g. `app=api`.
+    pub selector: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creation_timestamp: Option<Time>,
+}
+
+impl WorkloadSimpleInfo {
+    pub fn new(
+        kind: WorkloadKind,
+        metadata: ObjectMeta,
+        selector: Option<&LabelSelector>,
+        replicas: i32,
+        ready_replicas: i32,
+    ) -> Self {
+        Self {
+            kind,
+            namespace: metadata.namespace.unwrap_or_default(),
+            name: metadata.name.unwrap_or_default(),
+            replicas,
+            ready_replicas,
+            selector: selector.map(label_selector_string).unwrap_or_default(),
+            creation_timestamp: metadata.creation_timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodDetail {
+    pub namespace: String,
+    pub name: String,
+    pub uid: String,
+    pub node_name: String,
+    pub pod_ip: String,
+    pub host_ip: String,
+    pub phase: String,
+    pub qos_class: String,
+    pub service_account: String,
+    pub restart_policy: String,
+    /// Controller of the pod, e.g. `ReplicaSet/web-term-ffc789c85`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creation_timestamp: Option<Time>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<Time>,
+    pub terminating: bool,
+    pub conditions: Vec<PodConditionInfo>,
+    pub container_list: Vec<ContainerSimpleInfo>,
+    pub events: Vec<PodEventInfo>,
+    /// Absent when metrics-server isn't available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Vec<ContainerUsage>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodConditionInfo {
+    pub r#type: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_transition_time: Option<Time>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodEventInfo {
+    /// `Normal` or `Warning`.
+    pub r#type: String,
+    pub reason: String,
+    pub message: String,
+    pub count: i32,
+    pub source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_timestamp: Option<Time>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_timestamp: Option<Time>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerUsage {
+    pub container: String,
+    /// Raw quantities as reported by metrics-server, e.g. `12m` and `48Mi`.
+    pub cpu: String,
+    pub memory: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_millicores: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BroadcastReq {
+    /// Selects the target pods, e.g. `app=payments`. Only running pods are targeted, a blank
+    /// selector is rejected rather than matching every pod.
+    pub label_selector: String,
+    /// Passed as separate arguments, no shell is involved unless asked for.
+    pub command: Vec<String>,
+    /// Defaults to the first container of each pod.
+    pub container: Option<String>,
+    /// Capped at `AppConfig::broadcast_parallelism`.
+    pub parallelism: Option<usize>,
+    /// Overall timeout, capped at `AppConfig::broadcast_timeout_secs`.
+    pub timeout_secs: Option<u64>,
+}
+
+impl BroadcastReq {
+    /// Fails with 400 on an empty command or a selector that would match every pod.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.command.is_empty() {
+            return Err(HttpErr::new(StatusCode::BAD_REQUEST, "The command is empty.").into());
+        }
+        if self.label_selector.trim().is_empty() {
+            let message = "The label selector is empty.";
+            return Err(HttpErr::new(StatusCode::BAD_REQUEST, message).into());
+        }
+        Ok(())
+    }
+}
+
+/// One NDJSON line of the broadcast response, results stream in as pods complete and the
+/// summary comes last.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum BroadcastEvent {
+    Result(BroadcastResult),
+    Summary(BroadcastSummary),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BroadcastResult {
+    pub pod: String,
+    pub container: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub truncated: bool,
+    /// Set when the command couldn't be run or didn't finish before the timeout.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BroadcastResult {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none() && self.exit_code == Some(0)
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BroadcastSummary {
+    pub total: usize,
+    pub succeeded: usize,
+    /// Pods whose command failed or couldn't be run.
+    pub failed: Vec<String>,
+    /// Pods that hadn't finished when the overall timeout expired.
+    pub timed_out: Vec<String>,
+}
+
+impl BroadcastSummary {
+    /// Counts the pod of `result` once, as succeeded, timed out or failed.
+    pub fn record(&mut self, result: &BroadcastResult, timed_out: bool) {
+        if timed_out {
+            self.timed_out.push(result.pod.clone());
+        } else if result.succeeded() {
+            self.succeeded += 1;
+        } else {
+            self.failed.push(result.pod.clone());
+        }
+    }
 }
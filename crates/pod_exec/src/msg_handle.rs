@@ -1,39 +1,29 @@
 use common::tokio::net::TcpStream;
 use common::tokio::sync::mpsc;
 use common::tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
-use common::{axum, base64, futures_util, serde_json, tokio, tracing};
+use common::{anyhow, axum, futures_util, tokio, tracing};
 use common::{
     tokio::io::{stdin, AsyncBufReadExt as _, BufReader},
     tokio_tungstenite,
 };
 use futures_util::{SinkExt as _, StreamExt as _};
-use serde::{Deserialize, Serialize};
 use tokio_tungstenite::tungstenite::Message;
 
-const STD_INPUT_PREFIX: u8 = 0x00;
-const STD_OUTPUT_PREFIX_NORMAL: u8 = 0x01;
-const STD_OUTPUT_PREFIX_ERR: u8 = 0x02;
+use crate::{pipeline::Frame, protocol::TermMessage};
+
+pub const STD_INPUT_PREFIX: u8 = 0x00;
+pub const STD_OUTPUT_PREFIX_NORMAL: u8 = 0x01;
+pub const STD_OUTPUT_PREFIX_ERR: u8 = 0x02;
+pub const STATUS_PREFIX: u8 = 0x03;
+pub const RESIZE_PREFIX: u8 = 0x04;
 // const CR: u8 = 0x0D;
 const LF: u8 = 0x0A;
 
-#[derive(Debug, Serialize, Deserialize, Default)]
-struct Data {
-    rows: u16,
-    columns: u16,
-}
-
-#[derive(Debug, Serialize, Deserialize, Default)]
-struct ResizeMessage {
-    r#type: String,
-    data: Data,
-}
-
-// e.g. {"Width":80,"Height":24}
-#[derive(Debug, Serialize, Deserialize, Default)]
-#[serde(rename_all = "PascalCase")]
-struct TerminalSize {
-    width: u16,
-    height: u16,
+/// Wraps a server-side notice as terminal output so it shows up inline in the session.
+pub fn build_terminal_notice(notice: &str) -> Frame {
+    let mut data = vec![STD_OUTPUT_PREFIX_NORMAL];
+    data.extend_from_slice(format!("\r\n\x1b[33m{notice}\x1b[0m\r\n").as_bytes());
+    data
 }
 
 pub async fn stdin_reader(tx: mpsc::Sender<String>) {
@@ -53,6 +43,11 @@ pub async fn stdin_reader(tx: mpsc::Sender<String>) {
 
 pub trait MessageHandler {
     fn handle_message(self) -> String;
+
+    /// Input of the binary protocol, forwarded to kube as it is.
+    fn binary_frame(&self) -> Option<Frame> {
+        None
+    }
 }
 
 impl MessageHandler for axum::extract::ws::Message {
@@ -62,6 +57,17 @@ impl MessageHandler for axum::extract::ws::Message {
             _ => "".to_string(),
         }
     }
+
+    fn binary_frame(&self) -> Option<Frame> {
+        match self {
+            axum::extract::ws::Message::Binary(data)
+                if matches!(data.first(), Some(&STD_INPUT_PREFIX | &RESIZE_PREFIX)) =>
+            {
+                Some(data.clone())
+            }
+            _ => None,
+        }
+    }
 }
 
 impl MessageHandler for String {
@@ -73,7 +79,7 @@ impl MessageHandler for String {
 pub async fn handle_websocket<M>(
     kube_ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
     rx_web: &mut mpsc::Receiver<M>,
-    tx_kube: &mpsc::Sender<String>,
+    tx_kube: &mpsc::Sender<Frame>,
     is_closed: &mut bool,
     debug: Option<bool>,
 ) where
@@ -84,20 +90,41 @@ pub async fn handle_websocket<M>(
     loop {
         tokio::select! {
             Some(input) = rx_web.recv() => {
-                let input: String = input.handle_message();
                 step = 0;
                 chat_no += 1;
 
-                let resize_msg = build_resize_msg(input.clone());
-                let ascii_msg = build_ascii_msg(input, resize_msg, debug);
+                let ascii_msg = match input.binary_frame() {
+                    Some(frame) => frame,
+                    None => match build_kube_frame(input.handle_message(), debug) {
+                        Ok(frame) => frame,
+                        Err(err) => {
+                            tracing::warn!("Dropped malformed client frame: {}", err);
+                            continue;
+                        }
+                    },
+                };
 
+                tracing::debug!("=> sending message to kube: {:?}", ascii_msg);
                 let message = Message::Binary(ascii_msg);
                 if let Err(err) = kube_ws_stream.send(message).await {
                     tracing::error!("Failed to send binary message to kube ws: {}", err);
                     *is_closed = true;
+                    return;
                 }
             },
-            Some(Ok(msg)) = kube_ws_stream.next() => {
+            msg = kube_ws_stream.next() => {
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(err)) => {
+                        tracing::error!("Kube ws failed: {}", err);
+                        *is_closed = true;
+                        return;
+                    }
+                    None => {
+                        *is_closed = true;
+                        return;
+                    }
+                };
                 match msg {
                     Message::Text(text) => {
                         tracing::info!("Received text message: {}", text);
@@ -118,6 +145,7 @@ pub async fn handle_websocket<M>(
                     Message::Close(_) => {
                         tracing::info!("Received Close message");
                         *is_closed = true;
+                        return;
                     }
                     _ => {}
                 }
@@ -128,50 +156,49 @@ pub async fn handle_websocket<M>(
 
 pub async fn handle_binary_to_kube_channel(
     data: Vec<u8>,
-    tx_kube: &mpsc::Sender<String>,
+    tx_kube: &mpsc::Sender<Frame>,
     step: i32,
     cmd_debug: Option<bool>,
 ) -> bool {
-    let data_exist = !data.is_empty();
-    let data_prefix = data[0];
-    let data_value = data[1..].to_vec();
-
-    if data_exist {
-        match data_prefix {
-            STD_OUTPUT_PREFIX_NORMAL => {
-                let msg_ascii = data_value;
-                tracing::debug!("step {}, received org: {:?}", step, msg_ascii);
-
-                let msg_ascii = cmd_debug.map_or(msg_ascii.clone(), |debug| {
-                    if debug {
-                        local_dev_cmd_auxiliary_display(step, msg_ascii)
-                    } else {
-                        msg_ascii
-                    }
-                });
+    let Some((&data_prefix, data_value)) = data.split_first() else {
+        return false;
+    };
 
-                if !msg_ascii.is_empty() {
-                    let kube_msg = base64::Engine::encode(&base64::prelude::BASE64_STANDARD, data);
-                    let kube_msg = format!("1{kube_msg}");
-                    if tx_kube.send(kube_msg).await.is_err() {
-                        tracing::error!("Failed to send message to kube chanel");
-                    }
-                }
-            }
-            STD_OUTPUT_PREFIX_ERR => {
-                if let Ok(msg) = String::from_utf8(data_value) {
-                    tracing::info!("Received stderr: {}", msg);
+    match data_prefix {
+        STD_OUTPUT_PREFIX_NORMAL => {
+            let msg_ascii = data_value.to_vec();
+            tracing::debug!("step {}, received org: {:?}", step, msg_ascii);
+
+            let msg_ascii = cmd_debug.map_or(msg_ascii.clone(), |debug| {
+                if debug {
+                    local_dev_cmd_auxiliary_display(step, msg_ascii)
                 } else {
-                    tracing::info!("Failed to convert stderr to text");
+                    msg_ascii
                 }
+            });
+
+            // Waits while the consumer is behind, the exec stream isn't read meanwhile.
+            if !msg_ascii.is_empty() && tx_kube.send(data).await.is_err() {
+                tracing::error!("Failed to send message to kube chanel");
+            }
+        }
+        STATUS_PREFIX => {
+            if tx_kube.send(data).await.is_err() {
+                tracing::error!("Failed to send message to kube chanel");
             }
-            _ => {
-                tracing::info!("Unknown binary message prefix: {:?}", data_prefix);
+        }
+        STD_OUTPUT_PREFIX_ERR => {
+            if let Ok(msg) = std::str::from_utf8(data_value) {
+                tracing::info!("Received stderr: {}", msg);
+            } else {
+                tracing::info!("Failed to convert stderr to text");
             }
         }
-        return true;
+        _ => {
+            tracing::info!("Unknown binary message prefix: {:?}", data_prefix);
+        }
     }
-    false
+    true
 }
 
 fn local_dev_cmd_auxiliary_display(step: i32, mut msg_ascii: Vec<u8>) -> Vec<u8> {
@@ -195,52 +222,18 @@ fn local_dev_cmd_auxiliary_display(step: i32, mut msg_ascii: Vec<u8>) -> Vec<u8>
     msg_ascii
 }
 
-fn build_resize_msg(mut client_resize_msg: String) -> String {
-    if !client_resize_msg.starts_with('9') {
-        return Default::default();
-    }
-    if !client_resize_msg.is_empty() {
-        client_resize_msg.remove(0);
-    }
-    let client_msg = base64::Engine::decode(&base64::prelude::BASE64_STANDARD, client_resize_msg)
-        .unwrap_or_default();
-    let client_msg: String = client_msg.iter().map(|&c| c as char).collect();
-    let client_msg: ResizeMessage = serde_json::from_str(&client_msg).unwrap_or_default();
-    tracing::debug!("client resize msg => {:?}", client_msg);
-
-    let terminal_size = TerminalSize {
-        width: client_msg.data.columns,
-        height: client_msg.data.rows,
-    };
-    let resize_msg = serde_json::to_string(&terminal_size).unwrap_or_default();
-    tracing::debug!("{}", resize_msg);
-    resize_msg
-}
-
-fn build_ascii_msg(mut input: String, resize_msg: String, debug: Option<bool>) -> Vec<u8> {
-    let mut buffer = vec![];
-
-    if !resize_msg.is_empty() {
-        buffer.push(0x04);
-        let input = resize_msg.as_bytes();
-        buffer.extend_from_slice(input);
-        return buffer;
-    }
-
-    if debug.is_none_or(|debug| !debug) {
-        buffer.push(STD_INPUT_PREFIX);
-        input = input.chars().skip(1).collect();
-        let input =
-            base64::Engine::decode(&base64::prelude::BASE64_STANDARD, input).unwrap_or_default();
-        buffer.extend_from_slice(&input);
-    } else {
-        let input = input.trim().chars().collect::<String>();
-        let input = input.as_bytes();
-        buffer.extend_from_slice(input);
+/// Kube frame for a frame of the text protocol, or for a line typed in debug mode.
+fn build_kube_frame(input: String, debug: Option<bool>) -> Result<Frame, anyhow::Error> {
+    if debug.is_some_and(|debug| debug) {
+        let mut buffer = input.trim().as_bytes().to_vec();
         // buffer.push(CR);
         buffer.push(LF);
+        return Ok(buffer);
     }
 
-    tracing::debug!("=> sending message to kube: {:?}", buffer);
-    buffer
+    let message = TermMessage::decode_legacy(&input)?;
+    tracing::debug!("client msg => {:?}", message);
+    message
+        .to_kube_frame()
+        .ok_or_else(|| anyhow::anyhow!("no kube frame for {:?}", message))
 }
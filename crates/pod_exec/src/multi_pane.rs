@@ -0,0 +1,192 @@
+use axum::extract::ws::{Message, WebSocket};
+use common::{
+    anyhow,
+    axum::{self, http::StatusCode},
+    serde_json,
+    tokio::{
+        self,
+        sync::{broadcast, mpsc, watch},
+        task::JoinHandle,
+    },
+    tracing,
+};
+use context::context::Context;
+use kube::{
+    k8s_openapi::api::core::v1::Pod,
+    kube_runtime::{self, api::ListParams, Api},
+};
+use util::err::HttpErr;
+
+use crate::{
+    auth::User,
+    broadcast::broadcast_target,
+    connector::{ContainerCoords, TerminalOptions},
+    limits::SessionPermit,
+    model::{MultiPaneQuery, PaneFrame},
+    msg_handle::STD_OUTPUT_PREFIX_NORMAL,
+    pipeline::encode_frame,
+    services::{require_container, start_session},
+    session::{CloseReason, SessionFrame, SessionRegistry},
+};
+
+/// Resolves the containers behind a multi-pane session, pods are looked up by name when
+/// `pods` is given and by label otherwise.
+pub async fn resolve_panes(
+    ctx: &Context,
+    namespace: &str,
+    req: &MultiPaneQuery,
+) -> Result<Vec<ContainerCoords>, anyhow::Error> {
+    let api = Api::<Pod>::namespaced(ctx.kube_client.clone(), namespace);
+    let names = req.pod_names();
+
+    let pods = if !names.is_empty() {
+        let mut pods = Vec::with_capacity(names.len());
+        for name in names {
+            let pod = api.get(name).await.map_err(|err| match err {
+                kube_runtime::Error::Api(rsp) if rsp.code == 404 => {
+                    let message = format!("Pod {name} not found.");
+                    HttpErr::new(StatusCode::NOT_FOUND, &message).into()
+                }
+                err => anyhow::Error::from(err),
+            })?;
+            // Pods picked by name must run the container, unlike pods matched by label.
+            if let Some(container) = &req.container {
+                require_container(&pod, container)?;
+            }
+            pods.push(pod);
+        }
+        pods
+    } else if let Some(label_selector) = &req.label_selector {
+        let lp = ListParams::default()
+            .labels(label_selector)
+            .fields("status.phase=Running");
+        api.list(&lp).await?.items
+    } else {
+        let message = "Either pods or labelSelector is required.";
+        return Err(HttpErr::new(StatusCode::BAD_REQUEST, message).into());
+    };
+
+    let panes: Vec<ContainerCoords> = pods
+        .iter()
+        .filter_map(|p| broadcast_target(p, req.container.as_deref()))
+        .collect();
+    if panes.is_empty() {
+        let message = "No pod runs the requested container.";
+        return Err(HttpErr::new(StatusCode::NOT_FOUND, message).into());
+    }
+    if panes.len() > ctx.config.multi_pane_max {
+        let message = format!(
+            "{} pods selected, at most {} panes are allowed.",
+            panes.len(),
+            ctx.config.multi_pane_max
+        );
+        return Err(HttpErr::new(StatusCode::BAD_REQUEST, &message).into());
+    }
+    Ok(panes)
+}
+
+/// Opens one session per pane, see `SessionRegistry`, so panes are audited and ended by the
+/// session limits like any terminal. Every client message is fanned out to all of them and
+/// their output comes back as `PaneFrame` JSON, tagged with the pod it came from. The panes
+/// end with the socket, they aren't resumed.
+pub async fn handle_multi_pane_socket(
+    mut axum_socket: WebSocket,
+    panes: Vec<ContainerCoords>,
+    permits: Vec<SessionPermit>,
+    options: TerminalOptions,
+    user: User,
+    sessions: SessionRegistry,
+    ctx: Context,
+) {
+    let (tx_pane, mut rx_pane) = mpsc::channel::<PaneFrame>(100);
+    let mut pane_sessions = Vec::with_capacity(panes.len());
+    let mut tasks: Vec<JoinHandle<()>> = Vec::new();
+
+    for (coords, permit) in panes.into_iter().zip(permits) {
+        let (tx_web, rx_web) = mpsc::channel::<Message>(100);
+        if let Some(resize) = &options.resize {
+            let _ = tx_web.try_send(Message::Binary(resize.clone()));
+        }
+        let session = sessions.create(&user, coords, tx_web);
+        tasks.push(tokio::spawn(forward_pane_output(
+            session.subscribe(),
+            session.ended(),
+            session.coords.pod.clone(),
+            tx_pane.clone(),
+        )));
+        session.hold(permit);
+        start_session(&session, rx_web, &options, &sessions, &ctx).await;
+        pane_sessions.push(session);
+    }
+    // The receiver closes once every pane has ended.
+    drop(tx_pane);
+
+    loop {
+        tokio::select! {
+            client_msg = axum_socket.recv() => {
+                let Some(Ok(client_msg)) = client_msg else {
+                    tracing::info!("Client disconnected, closing {} panes", pane_sessions.len());
+                    break;
+                };
+                for session in &pane_sessions {
+                    session.try_send_input(&user, client_msg.clone());
+                }
+            },
+            frame = rx_pane.recv() => {
+                let Some(frame) = frame else {
+                    tracing::info!("Every pane has ended, closing the socket");
+                    let _ = axum_socket.send(Message::Close(None)).await;
+                    break;
+                };
+                let Ok(frame) = serde_json::to_string(&frame) else {
+                    continue;
+                };
+                if axum_socket.send(Message::Text(frame)).await.is_err() {
+                    tracing::info!("Client disconnected, failed to send message");
+                    break;
+                }
+            }
+        }
+    }
+
+    for task in tasks {
+        task.abort();
+    }
+    for session in pane_sessions {
+        sessions.remove(&session.id);
+        session.end(CloseReason::OwnerLeft);
+    }
+}
+
+/// Forwards the stdout of one pane's session until it ends, the exit status isn't shown.
+async fn forward_pane_output(
+    mut output: broadcast::Receiver<(u64, SessionFrame)>,
+    mut ended: watch::Receiver<Option<CloseReason>>,
+    pod: String,
+    tx_pane: mpsc::Sender<PaneFrame>,
+) {
+    loop {
+        let frame = tokio::select! {
+            biased;
+            frame = output.recv() => match frame {
+                Ok((_, frame)) => frame,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Pane {} missed {} frames", pod, skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            _ = ended.wait_for(Option::is_some) => return,
+        };
+        if frame.first() != Some(&STD_OUTPUT_PREFIX_NORMAL) {
+            continue;
+        }
+        let frame = PaneFrame {
+            pod: pod.clone(),
+            data: encode_frame(&frame),
+        };
+        if tx_pane.send(frame).await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Kube output travels as raw frames, the channel byte followed by the payload exactly as the
+//! API server sent it, and is only encoded for the browser at the socket. The channels between
+//! are bounded, a slow consumer stops the exec stream from being read instead of buffering.
+
+use common::{
+    axum::{
+        extract::ws::{Message, WebSocket},
+        http::HeaderValue,
+    },
+    base64,
+    tokio::sync::mpsc,
+};
+
+use crate::{msg_handle::STD_OUTPUT_PREFIX_NORMAL, protocol::TermMessage};
+
+/// A kube channel byte followed by its payload.
+pub type Frame = Vec<u8>;
+
+/// Frames queued between the exec stream and the browser before the stream is paused.
+pub const FRAME_CHANNEL_CAPACITY: usize = 100;
+
+/// Small frames are merged up to this size, bash echoing keystrokes sends one per byte.
+pub const COALESCE_MAX_BYTES: usize = 32 * 1024;
+
+/// Subprotocol of the text protocol, frames are base64 encoded behind a leading digit.
+pub const TEXT_PROTOCOL: &str = "echo-protocol";
+
+/// Subprotocol of the binary protocol, frames are sent as they are, the channel byte followed
+/// by the payload. Clients send stdin on channel 0 and resizes, `{"Width":80,"Height":24}`, on
+/// channel 4.
+pub const BINARY_PROTOCOL: &str = "kube-term.v2";
+
+/// Subprotocol of typed JSON messages, see `protocol::TermMessage`.
+pub const JSON_PROTOCOL: &str = "kube-term.json";
+
+/// Offered on terminal sockets, in order of preference when a client requests several.
+pub const TERMINAL_PROTOCOLS: [&str; 3] = [BINARY_PROTOCOL, JSON_PROTOCOL, TEXT_PROTOCOL];
+
+/// How frames are carried on a browser socket, picked from the negotiated subprotocol.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireProtocol {
+    #[default]
+    Text,
+    Binary,
+    Json,
+}
+
+impl WireProtocol {
+    pub fn of(socket: &WebSocket) -> Self {
+        Self::from_header(socket.protocol())
+    }
+
+    /// Clients that don't request a subprotocol get the text protocol.
+    pub fn from_header(protocol: Option<&HeaderValue>) -> Self {
+        match protocol.and_then(|p| p.to_str().ok()) {
+            Some(BINARY_PROTOCOL) => WireProtocol::Binary,
+            Some(JSON_PROTOCOL) => WireProtocol::Json,
+            _ => WireProtocol::Text,
+        }
+    }
+
+    /// The message for a kube frame, `None` for frames the protocol doesn't carry. The text
+    /// protocol only carries stdout.
+    pub fn encode(&self, frame: &[u8]) -> Option<Message> {
+        match self {
+            WireProtocol::Text => (frame.first() == Some(&STD_OUTPUT_PREFIX_NORMAL))
+                .then(|| Message::Text(encode_frame(frame))),
+            WireProtocol::Binary => Some(Message::Binary(frame.to_vec())),
+            WireProtocol::Json => TermMessage::from_kube_frame(frame).map(|m| m.into()),
+        }
+    }
+}
+
+/// Text frame for the browser, `1` followed by the base64 of the whole frame.
+pub fn encode_frame(frame: &[u8]) -> String {
+    let kube_msg = base64::Engine::encode(&base64::prelude::BASE64_STANDARD, frame);
+    format!("1{kube_msg}")
+}
+
+/// Appends the stdout frames already queued behind `frame`, without waiting for more, until
+/// `max_bytes` is reached. Returns a frame that couldn't be merged, to be sent next.
+pub fn coalesce(
+    frame: &mut Frame,
+    rx: &mut mpsc::Receiver<Frame>,
+    max_bytes: usize,
+) -> Option<Frame> {
+    if frame.first() != Some(&STD_OUTPUT_PREFIX_NORMAL) {
+        return None;
+    }
+    while frame.len() < max_bytes {
+        let next = rx.try_recv().ok()?;
+        if next.first() != Some(&STD_OUTPUT_PREFIX_NORMAL) || frame.len() + next.len() > max_bytes {
+            return Some(next);
+        }
+        frame.extend_from_slice(&next[1..]);
+    }
+    None
+}
+
+/// Drains `rx` into `sink` coalescing along the way. `sink` is awaited before the next frame
+/// is taken, which is what pushes back on the exec stream.
+pub async fn forward_frames<F, Fut>(mut rx: mpsc::Receiver<Frame>, max_bytes: usize, mut sink: F)
+where
+    F: FnMut(Frame) -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    let mut pending = None;
+    loop {
+        let mut frame = match pending.take() {
+            Some(frame) => frame,
+            None => match rx.recv().await {
+                Some(frame) => frame,
+                None => return,
+            },
+        };
+        pending = coalesce(&mut frame, &mut rx, max_bytes);
+        if !sink(frame).await {
+            return;
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use common::{anyhow, serde_json, tokio, tracing};
+use context::context::Context;
+use kube::{
+    k8s_openapi::api::core::v1::Event,
+    kube_runtime::{
+        api::{ApiResource, DynamicObject, GroupVersionKind, ListParams},
+        Api,
+    },
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::{
+    model::{ContainerUsage, PodConditionInfo, PodDetail, PodEventInfo},
+    services::{get_pod, pod_container_list},
+};
+
+/// How many of the most recent events are returned with the pod.
+const POD_EVENT_LIMIT: usize = 20;
+
+pub async fn get_pod_detail(
+    ctx: &Context,
+    namespace: &str,
+    pod_name: &str,
+) -> Result<PodDetail, anyhow::Error> {
+    let pod = get_pod(ctx, namespace, pod_name).await?;
+
+    let uid = pod.metadata.uid.clone().unwrap_or_default();
+    let (events, usage) = tokio::join!(
+        get_pod_events(ctx, namespace, pod_name, &uid),
+        get_pod_usage(ctx, namespace, pod_name),
+    );
+
+    let spec = pod.spec.clone().unwrap_or_default();
+    let status = pod.status.clone().unwrap_or_default();
+    let conditions = status
+        .conditions
+        .iter()
+        .flatten()
+        .map(|c| PodConditionInfo {
+            r#type: c.type_.clone(),
+            status: c.status.clone(),
+            reason: c.reason.clone(),
+            message: c.message.clone(),
+            last_transition_time: c.last_transition_time.clone(),
+        })
+        .collect();
+    let owner = pod
+        .metadata
+        .owner_references
+        .iter()
+        .flatten()
+        .find(|owner| owner.controller.unwrap_or_default())
+        .map(|owner| format!("{}/{}", owner.kind, owner.name));
+
+    Ok(PodDetail {
+        namespace: namespace.to_string(),
+        name: pod_name.to_string(),
+        uid,
+        node_name: spec.node_name.unwrap_or_default(),
+        pod_ip: status.pod_ip.unwrap_or_default(),
+        host_ip: status.host_ip.unwrap_or_default(),
+        phase: status.phase.unwrap_or("<unknown>".to_owned()),
+        qos_class: status.qos_class.unwrap_or_default(),
+        service_account: spec.service_account_name.unwrap_or_default(),
+        restart_policy: spec.restart_policy.unwrap_or_default(),
+        owner,
+        labels: pod.metadata.labels.clone().unwrap_or_default(),
+        creation_timestamp: pod.metadata.creation_timestamp.clone(),
+        start_time: status.start_time,
+        terminating: pod.metadata.deletion_timestamp.is_some(),
+        conditions,
+        container_list: pod_container_list(&pod),
+        events,
+        usage,
+    })
+}
+
+/// Most recent events whose involvedObject is the pod, newest first. Matched by uid, events
+/// of an earlier pod with the same name are left out. Errors are logged and yield an empty
+/// list, the detail view is still useful without them.
+async fn get_pod_events(
+    ctx: &Context,
+    namespace: &str,
+    pod_name: &str,
+    uid: &str,
+) -> Vec<PodEventInfo> {
+    let events: Api<Event> = Api::namespaced(ctx.kube_client.clone(), namespace);
+    let lp =
+        ListParams::default().fields(&format!("involvedObject.kind=Pod,involvedObject.uid={uid}"));
+    let mut events = match events.list(&lp).await {
+        Ok(events) => events.items,
+        Err(err) => {
+            tracing::warn!("Failed to list events of pod {}: {}", pod_name, err);
+            return Vec::new();
+        }
+    };
+
+    let last_seen = |e: &Event| {
+        e.last_timestamp
+            .as_ref()
+            .map(|t| t.0)
+            .or(e.event_time.as_ref().map(|t| t.0))
+    };
+    events.sort_by_key(|e| std::cmp::Reverse(last_seen(e)));
+    events
+        .into_iter()
+        .take(POD_EVENT_LIMIT)
+        .map(|e| PodEventInfo {
+            r#type: e.type_.clone().unwrap_or_default(),
+            reason: e.reason.clone().unwrap_or_default(),
+            message: e.message.clone().unwrap_or_default(),
+            count: e.count.unwrap_or(1),
+            source: e
+                .source
+                .as_ref()
+                .and_then(|s| s.component.clone())
+                .or(e.reporting_component.clone())
+                .unwrap_or_default(),
+            first_timestamp: e.first_timestamp.clone(),
+            last_timestamp: e.last_timestamp.clone(),
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+struct PodMetricsContainer {
+    name: String,
+    usage: BTreeMap<String, String>,
+}
+
+/// Current usage per container from metrics-server, `None` when it isn't installed or has
+/// no sample for the pod yet.
+async fn get_pod_usage(
+    ctx: &Context,
+    namespace: &str,
+    pod_name: &str,
+) -> Option<Vec<ContainerUsage>> {
+    let gvk = GroupVersionKind::gvk("metrics.k8s.io", "v1beta1", "PodMetrics");
+    let resource = ApiResource::from_gvk_with_plural(&gvk, "pods");
+    let metrics: Api<DynamicObject> =
+        Api::namespaced_with(ctx.kube_client.clone(), namespace, &resource);
+
+    let pod_metrics = match metrics.get(pod_name).await {
+        Ok(pod_metrics) => pod_metrics,
+        Err(err) => {
+            tracing::debug!("No metrics for pod {}: {}", pod_name, err);
+            return None;
+        }
+    };
+    let containers = pod_metrics.data.get("containers")?.clone();
+    let containers: Vec<PodMetricsContainer> = serde_json::from_value(containers).ok()?;
+
+    let usage = containers
+        .into_iter()
+        .map(|c| {
+            let cpu = c.usage.get("cpu").cloned().unwrap_or_default();
+            let memory = c.usage.get("memory").cloned().unwrap_or_default();
+            ContainerUsage {
+                container: c.name,
+                cpu_millicores: parse_quantity(&cpu).map(|cores| cores * 1000.0),
+                memory_bytes: parse_quantity(&memory).map(|bytes| bytes.round() as u64),
+                cpu,
+                memory,
+            }
+        })
+        .collect();
+    Some(usage)
+}
+
+/// Parses a Kubernetes resource quantity such as `250m`, `1.5`, `128Mi`, `2G` or `12e6`.
+pub fn parse_quantity(quantity: &str) -> Option<f64> {
+    let quantity = quantity.trim();
+    let split = quantity
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(quantity.len());
+    let (number, suffix) = quantity.split_at(split);
+    let number: f64 = number.parse().ok()?;
+
+    let multiplier = match suffix {
+        "" => 1.0,
+        "n" => 1e-9,
+        "u" => 1e-6,
+        "m" => 1e-3,
+        "k" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        "T" => 1e12,
+        "P" => 1e15,
+        "E" => 1e18,
+        "Ki" => 1024.0,
+        "Mi" => 1024.0_f64.powi(2),
+        "Gi" => 1024.0_f64.powi(3),
+        "Ti" => 1024.0_f64.powi(4),
+        "Pi" => 1024.0_f64.powi(5),
+        "Ei" => 1024.0_f64.powi(6),
+        // Decimal exponent, `E` alone is exa.
+        _ => {
+            let exponent: i32 = suffix.strip_prefix(['e', 'E'])?.parse().ok()?;
+            10.0_f64.powi(exponent)
+        }
+    };
+    Some(number * multiplier)
+}
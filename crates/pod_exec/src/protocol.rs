@@ -0,0 +1,212 @@
+//! Typed terminal messages, see PROTOCOL.md. On the `kube-term.json` subprotocol every frame
+//! is one JSON object `{"v":1,"type":...}`, parsed strictly: unknown types or fields, missing
+//! fields, invalid base64 and other versions are rejected instead of guessed at. The legacy
+//! text protocol is decoded into the same messages.
+
+use common::{
+    anyhow::{self, anyhow, bail},
+    axum::extract::ws::Message,
+    base64, serde_json,
+};
+use kube::k8s_openapi::apimachinery::pkg::apis::meta::v1::Status;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    connector::exit_code_from_status,
+    msg_handle::{RESIZE_PREFIX, STATUS_PREFIX, STD_INPUT_PREFIX, STD_OUTPUT_PREFIX_NORMAL},
+    pipeline::Frame,
+};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Process signals a client can ask for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Signal {
+    Sigint,
+    Sigterm,
+    Sigkill,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TermMessage {
+    /// Keystrokes, client to server.
+    Stdin {
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// Terminal output, server to client.
+    Stdout {
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// Both dimensions greater than 0, like the size on the upgrade request.
+    Resize {
+        rows: u16,
+        cols: u16,
+    },
+    /// Answered with a `pong`.
+    Ping,
+    Pong,
+    Signal {
+        signal: Signal,
+    },
+    /// A client closes its side, the server tells why the session ended before closing.
+    Close {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<u16>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// A frame the server rejected, or a failure on its side.
+    Error {
+        message: String,
+    },
+    /// The command exited, `exit_code` is missing when the API server didn't report one.
+    Status {
+        exit_code: Option<i32>,
+        message: Option<String>,
+    },
+    /// Sent to the owner once the session is created.
+    SessionInfo {
+        id: String,
+        token: String,
+        /// Seconds the session waits to be resumed after a disconnect, 0 when it doesn't, as for
+        /// anonymous users.
+        resume_within_secs: u64,
+        /// Whether other users can join, sessions of anonymous users can't be.
+        joinable: bool,
+    },
+}
+
+impl TermMessage {
+    /// Parses a frame of the JSON protocol.
+    pub fn decode(text: &str) -> Result<Self, anyhow::Error> {
+        let mut value: serde_json::Value = serde_json::from_str(text)?;
+        let object = value
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("message must be a JSON object"))?;
+        match object.remove("v").as_ref().and_then(|v| v.as_u64()) {
+            Some(v) if v == PROTOCOL_VERSION as u64 => {}
+            Some(v) => bail!("unsupported protocol version {v}"),
+            None => bail!("missing protocol version"),
+        }
+        let message = serde_json::from_value(value)?;
+        if let TermMessage::Resize { rows, cols } = message {
+            if rows == 0 || cols == 0 {
+                bail!("rows and cols must both be greater than 0");
+            }
+        }
+        Ok(message)
+    }
+
+    pub fn encode(&self) -> String {
+        let mut value = serde_json::to_value(self).expect("messages serialize to JSON");
+        if let Some(object) = value.as_object_mut() {
+            object.insert("v".to_string(), PROTOCOL_VERSION.into());
+        }
+        value.to_string()
+    }
+
+    /// Parses a frame of the text protocol: `9` followed by a base64 JSON resize, or any
+    /// character followed by base64 stdin.
+    pub fn decode_legacy(text: &str) -> Result<Self, anyhow::Error> {
+        let mut chars = text.chars();
+        let kind = chars.next().ok_or_else(|| anyhow!("empty frame"))?;
+        let payload = base64::Engine::decode(&base64::prelude::BASE64_STANDARD, chars.as_str())?;
+        if kind != '9' {
+            return Ok(TermMessage::Stdin { data: payload });
+        }
+        let resize: LegacyResize = serde_json::from_slice(&payload)?;
+        Ok(TermMessage::Resize {
+            rows: resize.data.rows,
+            cols: resize.data.columns,
+        })
+    }
+
+    /// The frame to send on the exec stream, for the messages that have one.
+    pub fn to_kube_frame(&self) -> Option<Frame> {
+        match self {
+            TermMessage::Stdin { data } => {
+                let mut frame = vec![STD_INPUT_PREFIX];
+                frame.extend_from_slice(data);
+                Some(frame)
+            }
+            TermMessage::Resize { rows, cols } => {
+                let size = TerminalSize {
+                    width: *cols,
+                    height: *rows,
+                };
+                let mut frame = vec![RESIZE_PREFIX];
+                frame.extend(serde_json::to_vec(&size).ok()?);
+                Some(frame)
+            }
+            _ => None,
+        }
+    }
+
+    /// The message for a frame of the exec stream, stdout and the exit status.
+    pub fn from_kube_frame(frame: &[u8]) -> Option<Self> {
+        let (&channel, data) = frame.split_first()?;
+        match channel {
+            STD_OUTPUT_PREFIX_NORMAL => Some(TermMessage::Stdout {
+                data: data.to_vec(),
+            }),
+            STATUS_PREFIX => {
+                let status: Status = serde_json::from_slice(data).ok()?;
+                Some(TermMessage::Status {
+                    exit_code: exit_code_from_status(&status),
+                    message: status.message,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn error(message: impl ToString) -> Self {
+        TermMessage::Error {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<TermMessage> for Message {
+    fn from(message: TermMessage) -> Self {
+        Message::Text(message.encode())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LegacyResizeSize {
+    rows: u16,
+    columns: u16,
+}
+
+// e.g. {"type":"resize","data":{"rows":24,"columns":80}}
+#[derive(Debug, Deserialize)]
+struct LegacyResize {
+    data: LegacyResizeSize,
+}
+
+// e.g. {"Width":80,"Height":24}
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct TerminalSize {
+    width: u16,
+    height: u16,
+}
+
+mod base64_bytes {
+    use common::base64::{prelude::BASE64_STANDARD, Engine as _};
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64_STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let data = String::deserialize(deserializer)?;
+        BASE64_STANDARD.decode(data).map_err(D::Error::custom)
+    }
+}
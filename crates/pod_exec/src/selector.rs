@@ -0,0 +1,100 @@
+use kube::k8s_openapi::api::core::v1::Pod;
+use std::collections::BTreeMap;
+
+/// The subset of label and field selectors the cache can evaluate in memory. Set-based label
+/// selectors and unknown field paths parse to `None` and are left to the API server.
+#[derive(Debug, PartialEq)]
+pub enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    NotExists(String),
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Selector(pub Vec<Requirement>);
+
+const POD_FIELDS: [&str; 4] = [
+    "metadata.name",
+    "metadata.namespace",
+    "spec.nodeName",
+    "status.phase",
+];
+
+impl Selector {
+    pub fn parse_labels(selector: &str) -> Option<Self> {
+        if selector.contains('(') {
+            return None;
+        }
+        split_terms(selector)
+            .map(parse_term)
+            .collect::<Option<_>>()
+            .map(Self)
+    }
+
+    pub fn parse_fields(selector: &str) -> Option<Self> {
+        let selector: Self = split_terms(selector)
+            .map(parse_term)
+            .collect::<Option<_>>()
+            .map(Self)?;
+        let supported = selector.0.iter().all(|requirement| match requirement {
+            Requirement::Equals(key, _) | Requirement::NotEquals(key, _) => {
+                POD_FIELDS.contains(&key.as_str())
+            }
+            Requirement::Exists(_) | Requirement::NotExists(_) => false,
+        });
+        supported.then_some(selector)
+    }
+
+    pub fn matches<'a>(&self, get: impl Fn(&str) -> Option<&'a str>) -> bool {
+        self.0.iter().all(|requirement| match requirement {
+            Requirement::Equals(key, value) => get(key) == Some(value.as_str()),
+            Requirement::NotEquals(key, value) => get(key) != Some(value.as_str()),
+            Requirement::Exists(key) => get(key).is_some(),
+            Requirement::NotExists(key) => get(key).is_none(),
+        })
+    }
+
+    pub fn matches_labels(&self, labels: Option<&BTreeMap<String, String>>) -> bool {
+        self.matches(|key| labels.and_then(|l| l.get(key)).map(String::as_str))
+    }
+
+    pub fn matches_pod_labels(&self, pod: &Pod) -> bool {
+        self.matches_labels(pod.metadata.labels.as_ref())
+    }
+
+    pub fn matches_pod_fields(&self, pod: &Pod) -> bool {
+        self.matches(|key| match key {
+            "metadata.name" => pod.metadata.name.as_deref(),
+            "metadata.namespace" => pod.metadata.namespace.as_deref(),
+            "spec.nodeName" => pod.spec.as_ref().and_then(|s| s.node_name.as_deref()),
+            "status.phase" => pod.status.as_ref().and_then(|s| s.phase.as_deref()),
+            _ => None,
+        })
+    }
+}
+
+fn split_terms(selector: &str) -> impl Iterator<Item = &str> {
+    selector.split(',').map(str::trim).filter(|t| !t.is_empty())
+}
+
+fn parse_term(term: &str) -> Option<Requirement> {
+    let requirement = if let Some((key, value)) = term.split_once("!=") {
+        Requirement::NotEquals(key.trim().to_string(), value.trim().to_string())
+    } else if let Some((key, value)) = term.split_once("==").or_else(|| term.split_once('=')) {
+        Requirement::Equals(key.trim().to_string(), value.trim().to_string())
+    } else if let Some(key) = term.strip_prefix('!') {
+        Requirement::NotExists(key.trim().to_string())
+    } else {
+        Requirement::Exists(term.to_string())
+    };
+
+    let key = match &requirement {
+        Requirement::Equals(key, _)
+        | Requirement::NotEquals(key, _)
+        | Requirement::Exists(key)
+        | Requirement::NotExists(key) => key,
+    };
+    let valid = !key.is_empty() && !key.contains(char::is_whitespace);
+    valid.then_some(requirement)
+}
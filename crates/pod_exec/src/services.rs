@@ -1,33 +1,125 @@
 use axum::extract::ws::WebSocket;
 use common::{
     anyhow,
-    axum::{self, extract::ws::Message},
+    axum::{self, extract::ws::Message, http::StatusCode},
+    chrono,
+    futures_util::{future, stream, StreamExt as _},
+    serde_json,
     tokio::{self, sync::mpsc},
     tracing,
 };
-use context::context::Context;
+use context::{cache::KubeCache, context::Context};
 use kube::{
-    k8s_openapi::api::core::v1::{Namespace, Pod},
-    kube_runtime::{api::ListParams, Api},
+    k8s_openapi::{
+        api::{
+            authorization::v1::{ResourceAttributes, SubjectAccessReview, SubjectAccessReviewSpec},
+            core::v1::{ContainerStatus, EphemeralContainer, Namespace, Pod},
+        },
+        apimachinery::pkg::apis::meta::v1::Time,
+    },
+    kube_runtime,
+    kube_runtime::{
+        api::{ListParams, ObjectList, Patch, PatchParams, PostParams},
+        runtime::{wait::await_condition, watcher, WatchStreamExt as _},
+        Api, Client as KubeClient,
+    },
     ServiceAccountToken,
 };
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::Arc,
+    time::Duration,
+};
+use util::err::HttpErr;
 
 use crate::{
+    auth::{Groups, User},
     connector::{self, ContainerCoordsOptional},
-    model::{ContainerQuery, ContainerRsp, ContainerSimpleInfo, NamespaceSimpleInfo},
+    limits::SessionPermit,
+    model::{
+        CacheCursor, ContainerKind, ContainerQuery, ContainerRsp, ContainerSimpleInfo,
+        ContainerStateInfo, ContainerStateKind, DebugQuery, NamespaceCursor, NamespaceQuery,
+        NamespaceSimpleInfo, PodWatchEvent, PodWatchEventType, WatchQuery,
+    },
     msg_handle,
+    pipeline::{forward_frames, Frame, WireProtocol, COALESCE_MAX_BYTES, FRAME_CHANNEL_CAPACITY},
+    protocol::TermMessage,
+    selector::Selector,
+    session::{CloseReason, SessionRegistry, SharedSession},
 };
 
-use connector::{pod_exec_connector, ContainerCoords, PodExecParams, PodExecUrl};
-use msg_handle::handle_websocket;
+use connector::{pod_exec_connector, ContainerCoords, PodExecParams, PodExecUrl, TerminalOptions};
+use msg_handle::{build_terminal_notice, handle_websocket};
+
+pub async fn get_ns_list(
+    req: NamespaceQuery,
+    user: &User,
+    groups: &Groups,
+    ctx: Context,
+) -> Result<Vec<NamespaceSimpleInfo>, anyhow::Error> {
+    let cached_namespaces = ctx
+        .cache
+        .as_ref()
+        .filter(|cache| cache.namespaces_ready())
+        .and_then(|cache| cache.namespaces.as_ref());
+    let cached_labels = match &req.label_selector {
+        Some(selector) => Selector::parse_labels(selector),
+        None => Some(Selector::default()),
+    };
+
+    let mut ns_list = match (cached_namespaces, cached_labels) {
+        (Some(store), Some(labels)) => store
+            .state()
+            .iter()
+            .filter(|ns| labels.matches_labels(ns.metadata.labels.as_ref()))
+            .map(|ns| (**ns).clone())
+            .collect(),
+        _ => {
+            let namespaces: Api<Namespace> = Api::all(ctx.kube_client.clone());
+            let mut lp = ListParams::default();
+            if let Some(label_selector) = &req.label_selector {
+                lp = lp.labels(label_selector);
+            }
+            namespaces.list(&lp).await?.items
+        }
+    };
+    ns_list.sort_by(|a, b| a.metadata.name.cmp(&b.metadata.name));
+
+    if let Some(search) = &req.search {
+        let search = search.to_lowercase();
+        ns_list.retain(|ns| {
+            ns.metadata
+                .name
+                .as_ref()
+                .is_some_and(|name| name.to_lowercase().contains(&search))
+        });
+    }
 
-pub async fn get_ns_list(ctx: Context) -> Result<Vec<NamespaceSimpleInfo>, anyhow::Error> {
-    let namespaces: Api<Namespace> = Api::all(ctx.kube_client.clone());
-    let lp = ListParams::default();
-    let ns_list = namespaces.list(&lp).await?;
+    if req.exec_only.unwrap_or(false) {
+        // Anonymous callers have no identity the API server could review.
+        user.require_authenticated()?;
+        let names: Vec<String> = ns_list
+            .iter()
+            .filter_map(|ns| ns.metadata.name.clone())
+            .collect();
+        let allowed = allowed_namespaces(&names, |ns| can_exec_in(&ctx, user, groups, ns)).await;
+        ns_list.retain(|ns| {
+            ns.metadata
+                .name
+                .as_ref()
+                .is_some_and(|name| allowed.contains(name))
+        });
+    }
+
+    let pod_counts = if req.with_pod_count.unwrap_or(false) {
+        Some(count_pods_per_namespace(&ctx).await?)
+    } else {
+        None
+    };
 
     let mut namespace_list = Vec::new();
-    for ns in ns_list.items {
+    for ns in ns_list {
         let ns_name = ns.metadata.name.as_deref().unwrap_or("<unknown>");
         let ns_uid = ns.metadata.uid.as_deref().unwrap_or("<unknown>");
         let resource_version = ns
@@ -35,95 +127,986 @@ pub async fn get_ns_list(ctx: Context) -> Result<Vec<NamespaceSimpleInfo>, anyho
             .resource_version
             .as_deref()
             .unwrap_or("<unknown>");
+        let phase = ns
+            .status
+            .as_ref()
+            .and_then(|status| status.phase.as_deref())
+            .unwrap_or("<unknown>");
 
-        let ns = NamespaceSimpleInfo {
+        let ns_info = NamespaceSimpleInfo {
             id: ns_uid.to_string(),
             name: ns_name.to_string(),
             resource_version: resource_version.to_string(),
             r#type: "Kube".to_string(),
+            phase: phase.to_string(),
+            pod_count: pod_counts
+                .as_ref()
+                .map(|counts| counts.get(ns_name).copied().unwrap_or_default()),
+            creation_timestamp: ns.metadata.creation_timestamp,
+            labels: ns.metadata.labels.unwrap_or_default(),
+            annotations: ns.metadata.annotations.unwrap_or_default(),
         };
-        namespace_list.push(ns);
+        namespace_list.push(ns_info);
     }
     Ok(namespace_list)
 }
 
+/// Access reviews in flight at once for one namespace list.
+const ACCESS_REVIEW_CONCURRENCY: usize = 8;
+
+/// The namespaces `can_exec` allows, asking about a few at a time.
+pub async fn allowed_namespaces<'a, F, Fut>(
+    namespaces: &'a [String],
+    can_exec: F,
+) -> HashSet<String>
+where
+    F: Fn(&'a str) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let reviews: Vec<_> = namespaces
+        .iter()
+        .map(|ns| {
+            let allowed = can_exec(ns);
+            async move { allowed.await.then(|| ns.clone()) }
+        })
+        .collect();
+    stream::iter(reviews)
+        .buffer_unordered(ACCESS_REVIEW_CONCURRENCY)
+        .filter_map(future::ready)
+        .collect()
+        .await
+}
+
+/// Asks the API server whether the caller may open exec sessions in `namespace`.
+async fn can_exec_in(ctx: &Context, user: &User, groups: &Groups, namespace: &str) -> bool {
+    let attributes = ResourceAttributes {
+        namespace: Some(namespace.to_string()),
+        verb: Some("create".to_string()),
+        resource: Some("pods".to_string()),
+        subresource: Some("exec".to_string()),
+        ..Default::default()
+    };
+    review_access(ctx, user, groups, attributes).await
+}
+
+/// Asks the API server whether the caller may list pods in `namespace`, or in every namespace
+/// when it is `None`.
+async fn can_list_pods_in(
+    ctx: &Context,
+    user: &User,
+    groups: &Groups,
+    namespace: Option<&str>,
+) -> bool {
+    let attributes = ResourceAttributes {
+        namespace: namespace.map(str::to_string),
+        verb: Some("list".to_string()),
+        resource: Some("pods".to_string()),
+        ..Default::default()
+    };
+    review_access(ctx, user, groups, attributes).await
+}
+
+/// A `SubjectAccessReview` for the caller, a failed review counts as denied.
+async fn review_access(
+    ctx: &Context,
+    user: &User,
+    groups: &Groups,
+    attributes: ResourceAttributes,
+) -> bool {
+    let namespace = attributes.namespace.clone().unwrap_or_default();
+    let reviews: Api<SubjectAccessReview> = Api::all(ctx.kube_client.clone());
+    let review = SubjectAccessReview {
+        spec: SubjectAccessReviewSpec {
+            user: Some(user.0.clone()),
+            groups: Some(groups.0.clone()),
+            resource_attributes: Some(attributes),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    match reviews.create(&PostParams::default(), &review).await {
+        Ok(review) => review.status.is_some_and(|status| status.allowed),
+        Err(err) => {
+            tracing::warn!(
+                "Access review for namespace {:?} failed: {}",
+                namespace,
+                err
+            );
+            false
+        }
+    }
+}
+
+async fn count_pods_per_namespace(ctx: &Context) -> Result<HashMap<String, usize>, anyhow::Error> {
+    let mut pod_counts = HashMap::new();
+    let cached_pods = ctx
+        .cache
+        .as_ref()
+        .filter(|cache| cache.pods_ready() && cache.covers(None));
+    let namespaces: Vec<Option<String>> = match cached_pods {
+        Some(cache) => cache
+            .pods
+            .state()
+            .iter()
+            .map(|p| p.metadata.namespace.clone())
+            .collect(),
+        None => {
+            let pods: Api<Pod> = Api::all(ctx.kube_client.clone());
+            pods.list_metadata(&ListParams::default())
+                .await?
+                .items
+                .into_iter()
+                .map(|p| p.metadata.namespace)
+                .collect()
+        }
+    };
+    for namespace in namespaces.into_iter().flatten() {
+        *pod_counts.entry(namespace).or_insert(0) += 1;
+    }
+    Ok(pod_counts)
+}
+
+/// Lists the containers of the namespaces the caller may list pods in, the others are reported
+/// as denied.
 pub async fn get_container_list(
-    req: ContainerQuery,
+    mut req: ContainerQuery,
+    user: &User,
+    groups: &Groups,
     ctx: Context,
 ) -> Result<ContainerRsp, anyhow::Error> {
-    let pods: Api<Pod> = Api::namespaced(
-        ctx.kube_client.clone(),
-        &req.ns.unwrap_or("default".to_owned()),
+    // Anonymous callers have no identity the API server could review.
+    user.require_authenticated()?;
+    let mut denied_namespaces = Vec::new();
+    let cluster_wide =
+        req.all_namespaces.unwrap_or(false) && can_list_pods_in(&ctx, user, groups, None).await;
+    if !cluster_wide {
+        let namespaces = if req.all_namespaces.unwrap_or(false) {
+            namespace_names(&ctx).await?
+        } else {
+            req.namespaces()
+        };
+        let allowed = allowed_namespaces(&namespaces, |ns| {
+            can_list_pods_in(&ctx, user, groups, Some(ns))
+        })
+        .await;
+        let (allowed, denied): (Vec<_>, Vec<_>) =
+            namespaces.into_iter().partition(|ns| allowed.contains(ns));
+        denied_namespaces = denied;
+        if allowed.is_empty() {
+            return Ok(ContainerRsp {
+                denied_namespaces,
+                ..Default::default()
+            });
+        }
+        req.all_namespaces = Some(false);
+        req.ns = Some(allowed.join(","));
+    }
+
+    let mut container_res = list_containers(req, ctx).await?;
+    denied_namespaces.append(&mut container_res.denied_namespaces);
+    container_res.denied_namespaces = denied_namespaces;
+    Ok(container_res)
+}
+
+/// Names of every namespace, sorted so a page token keeps pointing at the same one.
+async fn namespace_names(ctx: &Context) -> Result<Vec<String>, anyhow::Error> {
+    let cached = ctx
+        .cache
+        .as_ref()
+        .filter(|cache| cache.namespaces_ready())
+        .and_then(|cache| cache.namespaces.as_ref());
+    let mut names: Vec<String> = match cached {
+        Some(store) => store
+            .state()
+            .iter()
+            .filter_map(|ns| ns.metadata.name.clone())
+            .collect(),
+        None => {
+            let namespaces: Api<Namespace> = Api::all(ctx.kube_client.clone());
+            namespaces
+                .list(&ListParams::default())
+                .await?
+                .items
+                .into_iter()
+                .filter_map(|ns| ns.metadata.name)
+                .collect()
+        }
+    };
+    names.sort();
+    Ok(names)
+}
+
+async fn list_containers(req: ContainerQuery, ctx: Context) -> Result<ContainerRsp, anyhow::Error> {
+    let page_size = req
+        .page_size
+        .unwrap_or(ctx.config.list_page_size_default)
+        .clamp(1, ctx.config.list_page_size_max.max(1));
+
+    if let Some(cache) = ctx.cache.as_ref().filter(|cache| cache.pods_ready()) {
+        if let Some(container_res) = list_containers_from_cache(cache, &req, page_size) {
+            return Ok(container_res);
+        }
+    }
+
+    if req.all_namespaces.unwrap_or(false) {
+        let pods: Api<Pod> = Api::all(ctx.kube_client.clone());
+        return list_container_page(&pods, &req, page_size, req.page_token.as_deref())
+            .await
+            .map_err(page_token_err);
+    }
+
+    let namespaces = req.namespaces();
+    if let [namespace] = namespaces.as_slice() {
+        let pods: Api<Pod> = Api::namespaced(ctx.kube_client.clone(), namespace);
+        return list_container_page(&pods, &req, page_size, req.page_token.as_deref())
+            .await
+            .map_err(page_token_err);
+    }
+
+    // Several namespaces are paged through one after another, the page token records which
+    // namespace we are in and the continue token within it.
+    let mut cursor = match &req.page_token {
+        Some(token) => NamespaceCursor::decode(token)
+            .map_err(|_| HttpErr::new(StatusCode::BAD_REQUEST, "Invalid page token."))?,
+        None => NamespaceCursor::default(),
+    };
+    let mut denied_namespaces = Vec::new();
+    while let Some(namespace) = namespaces.get(cursor.index) {
+        let pods: Api<Pod> = Api::namespaced(ctx.kube_client.clone(), namespace);
+        match list_container_page(&pods, &req, page_size, cursor.token.as_deref()).await {
+            Ok(mut container_res) => {
+                let is_last_namespace = cursor.index + 1 == namespaces.len();
+                let page_token = match container_res.page_token.take() {
+                    Some(token) => Some(NamespaceCursor {
+                        index: cursor.index,
+                        token: Some(token),
+                    }),
+                    None if !is_last_namespace => Some(NamespaceCursor {
+                        index: cursor.index + 1,
+                        token: None,
+                    }),
+                    None => None,
+                };
+                container_res.page_token = page_token.map(|cursor| cursor.encode());
+                if !is_last_namespace {
+                    // The API server only knows about the namespace being listed.
+                    container_res.remaining_pod_count = None;
+                }
+                container_res.denied_namespaces = denied_namespaces;
+                return Ok(container_res);
+            }
+            Err(kube_runtime::Error::Api(err)) if err.code == 403 => {
+                tracing::warn!("Not allowed to list pods in namespace {}", namespace);
+                denied_namespaces.push(namespace.clone());
+                cursor = NamespaceCursor {
+                    index: cursor.index + 1,
+                    token: None,
+                };
+            }
+            Err(err) => return Err(page_token_err(err)),
+        }
+    }
+
+    Ok(ContainerRsp {
+        denied_namespaces,
+        ..Default::default()
+    })
+}
+
+/// Serves the list from the reflector cache, `None` when the cache can't answer the query
+/// (namespace out of scope, unsupported selector or a page token from the API server).
+fn list_containers_from_cache(
+    cache: &KubeCache,
+    req: &ContainerQuery,
+    page_size: u32,
+) -> Option<ContainerRsp> {
+    let all_namespaces = req.all_namespaces.unwrap_or(false);
+    let namespaces = req.namespaces();
+    let covered = if all_namespaces {
+        cache.covers(None)
+    } else {
+        namespaces.iter().all(|ns| cache.covers(Some(ns)))
+    };
+    if !covered {
+        return None;
+    }
+
+    let labels = match &req.label_selector {
+        Some(selector) => Selector::parse_labels(selector)?,
+        None => Selector::default(),
+    };
+    let fields = match &req.field_selector {
+        Some(selector) => Selector::parse_fields(selector)?,
+        None => Selector::default(),
+    };
+    let offset = match &req.page_token {
+        Some(token) => CacheCursor::decode(token).ok()?.offset,
+        None => 0,
+    };
+
+    let mut pods: Vec<_> = cache
+        .pods
+        .state()
+        .into_iter()
+        .filter(|p| {
+            all_namespaces
+                || p.metadata
+                    .namespace
+                    .as_ref()
+                    .is_some_and(|ns| namespaces.contains(ns))
+        })
+        .filter(|p| labels.matches_pod_labels(p) && fields.matches_pod_fields(p))
+        .collect();
+    pods.sort_by(|a, b| {
+        (&a.metadata.namespace, &a.metadata.name).cmp(&(&b.metadata.namespace, &b.metadata.name))
+    });
+
+    let mut container_list = Vec::new();
+    let mut next_offset = offset;
+    for pod in pods.iter().skip(offset) {
+        let page_full = if req.filters_containers() {
+            container_list.len() >= page_size as usize
+        } else {
+            next_offset - offset >= page_size as usize
+        };
+        if page_full {
+            break;
+        }
+        container_list.extend(
+            pod_container_list(pod)
+                .into_iter()
+                .filter(|c| req.matches(c)),
+        );
+        next_offset += 1;
+    }
+    let remaining = pods.len().saturating_sub(next_offset);
+
+    Some(ContainerRsp {
+        container_list,
+        pod_count: next_offset - offset,
+        remaining_pod_count: Some(remaining as i64),
+        page_token: (remaining > 0).then(|| {
+            CacheCursor {
+                offset: next_offset,
+            }
+            .encode()
+        }),
+        ..Default::default()
+    })
+}
+
+/// Lists one page of pods, `page_size` counts pods rather than containers.
+async fn list_container_page(
+    pods: &Api<Pod>,
+    req: &ContainerQuery,
+    page_size: u32,
+    page_token: Option<&str>,
+) -> Result<ContainerRsp, kube_runtime::Error> {
+    let mut lp = ListParams::default().limit(page_size);
+    if let Some(label_selector) = &req.label_selector {
+        lp = lp.labels(label_selector);
+    }
+    if let Some(field_selector) = &req.field_selector {
+        lp = lp.fields(field_selector);
+    }
+
+    collect_container_pages(req, page_size, page_token, |token| {
+        let lp = match token {
+            Some(token) => lp.clone().continue_token(&token),
+            None => lp.clone(),
+        };
+        async move { pods.list(&lp).await }
+    })
+    .await
+}
+
+/// Reads pages from `list_page` until `page_size` containers pass `req.matches`, or the pods
+/// run out, so filters the API server can't apply don't leave a page empty. Without such
+/// filters that is the first page.
+pub async fn collect_container_pages<F, Fut, E>(
+    req: &ContainerQuery,
+    page_size: u32,
+    page_token: Option<&str>,
+    mut list_page: F,
+) -> Result<ContainerRsp, E>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: Future<Output = Result<ObjectList<Pod>, E>>,
+{
+    let mut container_rsp = ContainerRsp::default();
+    let mut token = page_token.map(str::to_string);
+    loop {
+        let pods = list_page(token.take()).await?;
+        token = pods.metadata.continue_.clone().filter(|t| !t.is_empty());
+        tracing::info!("continue_koken {:?}", token);
+
+        container_rsp.pod_count += pods.items.len();
+        container_rsp.remaining_pod_count = pods.metadata.remaining_item_count;
+        container_rsp.container_list.extend(
+            pods.iter()
+                .flat_map(pod_container_list)
+                .filter(|c| req.matches(c)),
+        );
+        if token.is_none()
+            || !req.filters_containers()
+            || container_rsp.container_list.len() >= page_size as usize
+        {
+            break;
+        }
+    }
+    container_rsp.page_token = token;
+    Ok(container_rsp)
+}
+
+/// Continue tokens expire after a few minutes (HTTP 410), the client has to start over.
+fn page_token_err(err: kube_runtime::Error) -> anyhow::Error {
+    match err {
+        kube_runtime::Error::Api(err) if err.code == 410 => HttpErr::new(
+            StatusCode::GONE,
+            "Page token expired, restart listing from the first page.",
+        )
+        .into(),
+        err => err.into(),
+    }
+}
+
+/// Fetches a pod, fails with 404 when it doesn't exist.
+pub async fn get_pod(ctx: &Context, namespace: &str, pod_name: &str) -> Result<Pod, anyhow::Error> {
+    let pods: Api<Pod> = Api::namespaced(ctx.kube_client.clone(), namespace);
+    pods.get(pod_name).await.map_err(|err| match err {
+        kube_runtime::Error::Api(err) if err.code == 404 => {
+            let message = format!("Pod {namespace}/{pod_name} not found.");
+            HttpErr::new(StatusCode::NOT_FOUND, &message).into()
+        }
+        err => anyhow::Error::from(err),
+    })
+}
+
+/// Fails with 404 listing the pod's containers when `container` isn't one of them, before an
+/// exec the API server would only reject once the socket is open.
+pub fn require_container(pod: &Pod, container: &str) -> Result<(), anyhow::Error> {
+    let containers = pod_container_list(pod);
+    if containers
+        .iter()
+        .any(|c| c.container.container.as_deref() == Some(container))
+    {
+        return Ok(());
+    }
+    let names: Vec<_> = containers
+        .iter()
+        .filter_map(|c| c.container.container.as_deref())
+        .collect();
+    let message = format!(
+        "Container {container} not found in pod {}, it has: {}.",
+        pod.metadata.name.as_deref().unwrap_or_default(),
+        names.join(", ")
     );
+    Err(HttpErr::new(StatusCode::NOT_FOUND, &message).into())
+}
+
+/// Annotation naming the container `kubectl exec` picks when none is given.
+pub const DEFAULT_CONTAINER_ANNOTATION: &str = "kubectl.kubernetes.io/default-container";
 
-    let mut lp = ListParams::default().limit(req.page_size.unwrap_or(4).try_into().unwrap());
-    if let Some(token) = req.page_token {
-        lp = lp.continue_token(&token);
+/// The container to exec into when none is given, like kubectl: the one named by
+/// `DEFAULT_CONTAINER_ANNOTATION` if the pod has it, otherwise the only one. Fails with 409
+/// listing the containers to choose from.
+pub fn default_container(pod: &Pod) -> Result<String, anyhow::Error> {
+    let containers = pod
+        .spec
+        .as_ref()
+        .map(|spec| spec.containers.as_slice())
+        .unwrap_or_default();
+    let annotated = pod
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(DEFAULT_CONTAINER_ANNOTATION));
+    if let Some(container) = annotated.and_then(|name| containers.iter().find(|c| &c.name == name))
+    {
+        return Ok(container.name.clone());
     }
+    if let [container] = containers {
+        return Ok(container.name.clone());
+    }
+
+    let names: Vec<_> = containers.iter().map(|c| c.name.as_str()).collect();
+    let message = format!(
+        "Pod {} has several containers, choose one of: {}.",
+        pod.metadata.name.as_deref().unwrap_or_default(),
+        names.join(", ")
+    );
+    Err(HttpErr::new(StatusCode::CONFLICT, &message).into())
+}
+
+/// Flattens a pod into one row per init, regular and ephemeral container, joined with the
+/// matching container status.
+pub fn pod_container_list(pod: &Pod) -> Vec<ContainerSimpleInfo> {
+    let namespace = pod.metadata.namespace.clone().unwrap_or_default();
+    let pod_name = pod.metadata.name.clone().unwrap_or_default();
+    let pod_status = pod.status.clone().unwrap_or_default();
+    let pod_ip = pod_status.pod_ip.unwrap_or("<unkonwn>".to_owned());
+    let pod_phase = pod_status.phase.unwrap_or("<unkonwn>".to_owned());
 
-    let pods = pods.list(&lp).await?;
+    let Some(spec) = &pod.spec else {
+        return Vec::new();
+    };
+    let node_name = spec.node_name.clone().unwrap_or_default();
 
-    let continue_token = &pods.metadata.continue_;
-    tracing::info!("continue_koken {:?}", continue_token);
+    let init_containers = spec
+        .init_containers
+        .iter()
+        .flatten()
+        .map(|c| (ContainerKind::Init, &c.name, &c.image));
+    let containers = spec
+        .containers
+        .iter()
+        .map(|c| (ContainerKind::Container, &c.name, &c.image));
+    let ephemeral_containers = spec
+        .ephemeral_containers
+        .iter()
+        .flatten()
+        .map(|c| (ContainerKind::Ephemeral, &c.name, &c.image));
 
     let mut container_list = Vec::new();
-    for p in &pods {
-        let namespace = p.metadata.namespace.clone().unwrap_or_default();
-        let pod_name = p.metadata.name.clone().unwrap_or_default();
-        let pod_status = p.status.clone().unwrap_or_default();
-        let pod_ip = pod_status.pod_ip.unwrap_or("<unkonwn>".to_owned());
-        let pod_phase = pod_status.phase.unwrap_or("<unkonwn>".to_owned());
-
-        if let Some(spec) = &p.spec {
-            for container in &spec.containers {
-                let container_name = container.name.clone();
-                let container_image = container.image.clone().unwrap_or("<unkonwn>".to_owned());
-
-                tracing::info!(
-                    "namespace: {:?}, podname: {:?}, container_name: {:?}",
-                    namespace,
-                    pod_name,
-                    container_name
-                );
-
-                let container_coords = ContainerCoordsOptional {
-                    namespace: Some(namespace.clone()),
-                    pod: Some(pod_name.clone()),
-                    container: Some(container_name),
+    for (kind, container_name, container_image) in init_containers
+        .chain(containers)
+        .chain(ephemeral_containers)
+    {
+        let container_image = container_image.clone().unwrap_or("<unkonwn>".to_owned());
+        let statuses = match kind {
+            ContainerKind::Init => &pod_status.init_container_statuses,
+            ContainerKind::Container => &pod_status.container_statuses,
+            ContainerKind::Ephemeral => &pod_status.ephemeral_container_statuses,
+        };
+        let status = statuses
+            .iter()
+            .flatten()
+            .find(|s| &s.name == container_name);
+
+        tracing::debug!(
+            "namespace: {:?}, podname: {:?}, container_name: {:?}",
+            namespace,
+            pod_name,
+            container_name
+        );
+
+        let (container_state, started_at) = container_state_info(status);
+        let container_coords = ContainerCoordsOptional {
+            namespace: Some(namespace.clone()),
+            pod: Some(pod_name.clone()),
+            container: Some(container_name.clone()),
+        };
+        container_list.push(ContainerSimpleInfo {
+            container: container_coords,
+            container_kind: kind,
+            pod_ip: pod_ip.clone(),
+            pod_phase: pod_phase.clone(),
+            node_name: node_name.clone(),
+            container_image,
+            executable: container_state.state == ContainerStateKind::Running,
+            container_state,
+            ready: status.is_some_and(|s| s.ready),
+            restart_count: status.map_or(0, |s| s.restart_count),
+            started_at,
+        });
+    }
+    container_list
+}
+
+fn container_state_info(status: Option<&ContainerStatus>) -> (ContainerStateInfo, Option<Time>) {
+    let Some(state) = status.and_then(|s| s.state.as_ref()) else {
+        return Default::default();
+    };
+
+    if let Some(running) = &state.running {
+        let info = ContainerStateInfo {
+            state: ContainerStateKind::Running,
+            ..Default::default()
+        };
+        (info, running.started_at.clone())
+    } else if let Some(waiting) = &state.waiting {
+        let info = ContainerStateInfo {
+            state: ContainerStateKind::Waiting,
+            reason: waiting.reason.clone(),
+            message: waiting.message.clone(),
+            exit_code: None,
+        };
+        (info, None)
+    } else if let Some(terminated) = &state.terminated {
+        let info = ContainerStateInfo {
+            state: ContainerStateKind::Terminated,
+            reason: terminated.reason.clone(),
+            message: terminated.message.clone(),
+            exit_code: Some(terminated.exit_code),
+        };
+        (info, terminated.started_at.clone())
+    } else {
+        Default::default()
+    }
+}
+
+/// Attaches an ephemeral debug container to a running pod and waits until it is running.
+/// Returns the name of the new container so a terminal session can be opened against it.
+pub async fn create_debug_container(
+    ctx: &Context,
+    coords: &ContainerCoords,
+    req: DebugQuery,
+) -> Result<String, anyhow::Error> {
+    let pods: Api<Pod> = Api::namespaced(ctx.kube_client.clone(), &coords.namespace);
+    let container_name = format!("debugger-{:x}", chrono::Utc::now().timestamp_millis());
+
+    let ephemeral_container = debug_container_spec(&container_name, req, &ctx.config.debug_image);
+    let patch = serde_json::json!({
+        "spec": {
+            "ephemeralContainers": [ephemeral_container]
+        }
+    });
+    pods.patch_ephemeral_containers(
+        &coords.pod,
+        &PatchParams::default(),
+        &Patch::Strategic(patch),
+    )
+    .await?;
+    tracing::info!(
+        "Ephemeral container {} added to pod {}/{}",
+        container_name,
+        coords.namespace,
+        coords.pod
+    );
+
+    let timeout = Duration::from_secs(ctx.config.debug_timeout_secs);
+    let condition = {
+        let container_name = container_name.clone();
+        move |pod: Option<&Pod>| debug_container_started(pod, &container_name).is_some()
+    };
+    let started = await_condition(pods, &coords.pod, condition);
+    match tokio::time::timeout(timeout, started).await {
+        Ok(Ok(pod)) => debug_container_started(pod.as_ref(), &container_name)
+            .unwrap_or(Ok(()))
+            .map(|_| container_name),
+        Ok(Err(err)) => Err(err.into()),
+        Err(_) => {
+            let message = format!(
+                "Ephemeral container {} did not start within {}s.",
+                container_name,
+                timeout.as_secs()
+            );
+            Err(HttpErr::new(StatusCode::GATEWAY_TIMEOUT, &message).into())
+        }
+    }
+}
+
+/// Waiting reasons after which a container doesn't start without a change to its spec.
+const CONTAINER_START_FAILURES: [&str; 5] = [
+    "ErrImagePull",
+    "ImagePullBackOff",
+    "InvalidImageName",
+    "CreateContainerError",
+    "CreateContainerConfigError",
+];
+
+/// The ephemeral container added for `req`, with a TTY so the terminal can attach to it.
+pub fn debug_container_spec(
+    container_name: &str,
+    req: DebugQuery,
+    default_image: &str,
+) -> EphemeralContainer {
+    EphemeralContainer {
+        name: container_name.to_string(),
+        image: Some(req.image.unwrap_or(default_image.to_string())),
+        target_container_name: req.target_container_name,
+        stdin: Some(true),
+        tty: Some(true),
+        ..Default::default()
+    }
+}
+
+/// `Ok` once the debug container runs, an error once it can't anymore: it terminated, its
+/// image can't be pulled or the pod is gone. `None` while it is still starting.
+pub fn debug_container_started(
+    pod: Option<&Pod>,
+    container_name: &str,
+) -> Option<Result<(), anyhow::Error>> {
+    let Some(pod) = pod else {
+        let message = "The pod was deleted while the debug container was starting.";
+        return Some(Err(HttpErr::new(StatusCode::NOT_FOUND, message).into()));
+    };
+    let state = pod
+        .status
+        .as_ref()
+        .and_then(|status| status.ephemeral_container_statuses.as_ref())
+        .and_then(|statuses| statuses.iter().find(|s| s.name == container_name))
+        .and_then(|s| s.state.as_ref())?;
+
+    if state.running.is_some() {
+        return Some(Ok(()));
+    }
+    if let Some(terminated) = &state.terminated {
+        let message = format!(
+            "Ephemeral container {} exited with code {}: {}",
+            container_name,
+            terminated.exit_code,
+            terminated.reason.as_deref().unwrap_or("unknown reason")
+        );
+        return Some(Err(HttpErr::new(StatusCode::CONFLICT, &message).into()));
+    }
+    let waiting = state.waiting.as_ref()?;
+    let reason = waiting.reason.as_deref()?;
+    if !CONTAINER_START_FAILURES.contains(&reason) {
+        return None;
+    }
+    let message = format!(
+        "Ephemeral container {} can't start, {}: {}",
+        container_name,
+        reason,
+        waiting.message.as_deref().unwrap_or_default()
+    );
+    Some(Err(HttpErr::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        &message,
+    )
+    .into()))
+}
+
+/// Streams pod add/modify/delete events for the requested namespaces until the client leaves.
+pub async fn handle_watch_socket(mut axum_socket: WebSocket, req: WatchQuery, ctx: Context) {
+    let mut config = watcher::Config::default();
+    if let Some(label_selector) = &req.label_selector {
+        config = config.labels(label_selector);
+    }
+    if let Some(field_selector) = &req.field_selector {
+        config = config.fields(field_selector);
+    }
+
+    let apis: Vec<Api<Pod>> = if req.all_namespaces.unwrap_or(false) {
+        vec![Api::all(ctx.kube_client.clone())]
+    } else {
+        req.namespaces()
+            .iter()
+            .map(|ns| Api::namespaced(ctx.kube_client.clone(), ns))
+            .collect()
+    };
+    // Every watcher relists on its own, its events are tagged with its index.
+    let mut watch_states: Vec<_> = apis.iter().map(|_| PodWatchState::default()).collect();
+    let mut events = stream::select_all(apis.into_iter().enumerate().map(|(index, api)| {
+        watcher(api, config.clone())
+            .default_backoff()
+            .map(move |event| (index, event))
+            .boxed()
+    }));
+
+    loop {
+        tokio::select! {
+            client_msg = axum_socket.recv() => {
+                match client_msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        tracing::info!("Watch client disconnected");
+                        return;
+                    }
+                    Some(Ok(_)) => {}
+                }
+            },
+            event = events.next() => {
+                let (index, event) = match event {
+                    Some((index, Ok(event))) => (index, event),
+                    Some((_, Err(err))) => {
+                        tracing::warn!("Pod watch error, retrying: {}", err);
+                        continue;
+                    }
+                    None => return,
                 };
-                let container = ContainerSimpleInfo {
-                    container: container_coords,
-                    pod_ip: pod_ip.clone(),
-                    pod_phase: pod_phase.clone(),
-                    container_image,
+                for watch_event in watch_states[index].apply(event) {
+                    let watch_msg = serde_json::to_string(&watch_event).unwrap_or_default();
+                    if axum_socket.send(Message::Text(watch_msg)).await.is_err() {
+                        tracing::info!("Watch client disconnected, failed to send message");
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Pods one watcher has reported, by uid. A relist after a watch gap only lists the pods that
+/// still exist, the ones missing from it are reported as deleted once it is done.
+#[derive(Default)]
+pub struct PodWatchState {
+    known_pods: HashMap<String, Pod>,
+    relisted: Option<HashSet<String>>,
+}
+
+impl PodWatchState {
+    pub fn apply(&mut self, event: watcher::Event<Pod>) -> Vec<PodWatchEvent> {
+        match event {
+            watcher::Event::Init => {
+                self.relisted = Some(HashSet::new());
+                Vec::new()
+            }
+            watcher::Event::InitApply(pod) | watcher::Event::Apply(pod) => {
+                let uid = pod.metadata.uid.clone().unwrap_or_default();
+                if let Some(relisted) = &mut self.relisted {
+                    relisted.insert(uid.clone());
+                }
+                let event_type = match self.known_pods.insert(uid, pod.clone()) {
+                    None => PodWatchEventType::Added,
+                    Some(_) => PodWatchEventType::Modified,
                 };
-                container_list.push(container)
+                vec![pod_watch_event(event_type, &pod)]
+            }
+            watcher::Event::Delete(pod) => {
+                self.known_pods
+                    .remove(pod.metadata.uid.as_deref().unwrap_or_default());
+                vec![pod_watch_event(PodWatchEventType::Deleted, &pod)]
+            }
+            watcher::Event::InitDone => {
+                let relisted = self.relisted.take().unwrap_or_default();
+                let gone: Vec<_> = self
+                    .known_pods
+                    .keys()
+                    .filter(|uid| !relisted.contains(*uid))
+                    .cloned()
+                    .collect();
+                gone.iter()
+                    .filter_map(|uid| self.known_pods.remove(uid))
+                    .map(|pod| pod_watch_event(PodWatchEventType::Deleted, &pod))
+                    .collect()
             }
         }
     }
+}
 
-    let container_res = ContainerRsp {
-        container_list,
-        page_token: continue_token.clone(),
+fn pod_watch_event(event_type: PodWatchEventType, pod: &Pod) -> PodWatchEvent {
+    PodWatchEvent {
+        r#type: event_type,
+        namespace: pod.metadata.namespace.clone().unwrap_or_default(),
+        pod: pod.metadata.name.clone().unwrap_or_default(),
+        terminating: pod.metadata.deletion_timestamp.is_some(),
+        container_list: pod_container_list(pod),
+    }
+}
+
+/// Writes a notice into the terminal once the pod behind the session starts terminating.
+pub async fn notify_pod_termination(
+    kube_client: KubeClient,
+    namespace: String,
+    pod: String,
+    tx_kube: mpsc::WeakSender<Frame>,
+) {
+    let pods: Api<Pod> = Api::namespaced(kube_client, &namespace);
+    let config = watcher::Config::default().fields(&format!("metadata.name={pod}"));
+    let mut events = watcher(pods, config).default_backoff().boxed();
+
+    while let Some(event) = events.next().await {
+        let terminating = match event {
+            Ok(watcher::Event::Apply(p)) | Ok(watcher::Event::InitApply(p)) => {
+                p.metadata.deletion_timestamp.is_some()
+            }
+            Ok(watcher::Event::Delete(_)) => true,
+            _ => false,
+        };
+        if terminating {
+            tracing::info!("Pod {}/{} is terminating", namespace, pod);
+            let notice = build_terminal_notice(&format!(
+                "Pod {pod} is being terminated, the session will end soon."
+            ));
+            // The exec stream may have closed already, then there is nobody to tell.
+            let Some(tx_kube) = tx_kube.upgrade() else {
+                return;
+            };
+            if tx_kube.send(notice).await.is_err() {
+                tracing::error!("Failed to send message to kube chanel");
+            }
+            return;
+        }
+    }
+}
+
+pub async fn handle_socket(
+    mut axum_socket: WebSocket,
+    coords: ContainerCoords,
+    options: TerminalOptions,
+    user: User,
+    permit: SessionPermit,
+    sessions: SessionRegistry,
+    ctx: Context,
+) {
+    let (tx_web, rx_web) = mpsc::channel::<Message>(100);
+    // First on the exec stream once it is open, the channel is still empty.
+    if let Some(resize) = &options.resize {
+        let _ = tx_web.try_send(Message::Binary(resize.clone()));
+    }
+
+    let session = sessions.create(&user, coords, tx_web);
+    let output = session.subscribe();
+    session.hold(permit);
+    start_session(&session, rx_web, &options, &sessions, &ctx).await;
+
+    // Resuming needs an authenticated user, sessions of anonymous users end with the socket.
+    let grace_secs = if user.is_anonymous() {
+        0
+    } else {
+        ctx.config.session_grace_secs
+    };
+    let protocol = WireProtocol::of(&axum_socket);
+    let greeting = if protocol == WireProtocol::Json {
+        Some(
+            TermMessage::SessionInfo {
+                id: session.id.clone(),
+                token: session.token.clone(),
+                resume_within_secs: grace_secs,
+                joinable: !user.is_anonymous(),
+            }
+            .into(),
+        )
+    } else if user.is_anonymous() {
+        None
+    } else {
+        let notice = format!(
+            "Session {} can be joined by other users, resume it within {grace_secs}s of a disconnect with token {}.",
+            session.id, session.token
+        );
+        protocol.encode(&build_terminal_notice(&notice))
     };
+    if let Some(greeting) = greeting {
+        if axum_socket.send(greeting).await.is_err() {
+            tracing::info!("Client disconnected, failed to send message");
+        }
+    }
 
-    Ok(container_res)
+    session.attach(&mut axum_socket, &user, output).await;
+    release_session(session, sessions, grace_secs);
 }
 
-pub async fn handle_socket(mut axum_socket: WebSocket, coords: ContainerCoords) {
+/// Opens the exec stream of `session`, fed from `rx_web`, and starts the tasks that live as
+/// long as the session: publishing kube output, the termination notice and the session limits.
+/// The session ends with `CloseReason::Exited` once the exec stream closes or fails to open.
+pub async fn start_session(
+    session: &Arc<SharedSession>,
+    mut rx_web: mpsc::Receiver<Message>,
+    options: &TerminalOptions,
+    sessions: &SessionRegistry,
+    ctx: &Context,
+) {
     let sat = ServiceAccountToken::new();
+    let coords = &session.coords;
+    let pod_exec_url = PodExecUrl::default().get_exec_url(&sat.kube_host, &sat.kube_port, coords);
+    let pod_exec_params =
+        PodExecParams::default().get_pod_exec_params(coords, options, Some(&session.id));
+    let (tx_kube, rx_kube) = mpsc::channel(FRAME_CHANNEL_CAPACITY);
 
-    let pod_exec_url = PodExecUrl::default().get_exec_url(&sat.kube_host, &sat.kube_port, &coords);
-    let pod_exec_params = PodExecParams::default().get_pod_exec_params(&coords);
-
-    let (tx_web, mut rx_web) = mpsc::channel::<Message>(100);
-    let (tx_kube, mut rx_kube) = mpsc::channel(100);
+    session.add_task(tokio::spawn(notify_pod_termination(
+        ctx.kube_client.clone(),
+        coords.namespace.clone(),
+        coords.pod.clone(),
+        tx_kube.downgrade(),
+    )));
 
     let conn = pod_exec_connector(&sat, &pod_exec_url, &pod_exec_params).await;
     match conn {
         Ok(mut kube_ws_stream) => {
             let mut closed = false;
-            tokio::spawn(async move {
+            session.add_task(tokio::spawn(async move {
                 handle_websocket(
                     &mut kube_ws_stream,
                     &mut rx_web,
@@ -132,35 +1115,149 @@ pub async fn handle_socket(mut axum_socket: WebSocket, coords: ContainerCoords)
                     None,
                 )
                 .await;
-            });
+            }));
         }
         Err(err) => {
-            tracing::error!("ERROR, {}", err)
+            tracing::error!("Failed to exec into {}, {}", coords.pod, err);
+            let notice = build_terminal_notice(&format!("Failed to connect: {err}"));
+            let _ = tx_kube.send(notice).await;
         }
     };
 
+    session.add_task(tokio::spawn(enforce_session_limits(
+        session.clone(),
+        sessions.clone(),
+        Duration::from_secs(ctx.config.session_idle_timeout_secs),
+        Duration::from_secs(ctx.config.session_max_duration_secs),
+        Duration::from_secs(ctx.config.session_warning_secs),
+    )));
+
+    let publisher = session.clone();
+    let (exited, sessions) = (session.clone(), sessions.clone());
+    session.add_task(tokio::spawn(async move {
+        forward_frames(rx_kube, COALESCE_MAX_BYTES, move |frame| {
+            let publisher = publisher.clone();
+            async move {
+                publisher.publish_output(frame).await;
+                true
+            }
+        })
+        .await;
+        // Every sender is gone, the exec stream closed or never opened.
+        tracing::info!("Exec stream of session {} closed", exited.id);
+        sessions.remove(&exited.id);
+        exited.end(CloseReason::Exited);
+    }));
+}
+
+/// Reattaches the owner to a session within its grace period, replaying missed output.
+pub async fn handle_resume_socket(
+    mut axum_socket: WebSocket,
+    session: Arc<SharedSession>,
+    user: User,
+    sessions: SessionRegistry,
+    ctx: Context,
+) {
+    let (missed, output) = session.resume();
+    tracing::info!(
+        "Resume session {}, replaying {} frames",
+        session.id,
+        missed.len()
+    );
+    let protocol = WireProtocol::of(&axum_socket);
+    for message in missed.iter().filter_map(|frame| protocol.encode(frame)) {
+        if axum_socket.send(message).await.is_err() {
+            tracing::info!("Client disconnected, failed to send message");
+            break;
+        }
+    }
+
+    session.attach(&mut axum_socket, &user, output).await;
+    release_session(session, sessions, ctx.config.session_grace_secs);
+}
+
+/// Keeps the session and its kube stream alive for `grace_secs` once the owner's last
+/// connection is gone, ending it unless the owner resumes in time.
+fn release_session(session: Arc<SharedSession>, sessions: SessionRegistry, grace_secs: u64) {
+    let Some(epoch) = session.owner_detached() else {
+        return;
+    };
+    if grace_secs == 0 || session.is_ended() {
+        sessions.remove(&session.id);
+        session.end(CloseReason::OwnerLeft);
+        return;
+    }
+
+    session.publish(build_terminal_notice(&format!(
+        "{} disconnected, the session is kept for {grace_secs}s.",
+        session.owner
+    )));
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(grace_secs)).await;
+        if session.owner_detached() == Some(epoch) {
+            tracing::info!("Session {} wasn't resumed, ending it", session.id);
+            sessions.remove(&session.id);
+            session.end(CloseReason::NotResumed);
+        }
+    });
+}
+
+/// Warns in the terminal ahead of time and then ends the session once it has been idle for
+/// `idle_timeout` or open for `max_duration`. A zero duration disables the limit.
+async fn enforce_session_limits(
+    session: Arc<SharedSession>,
+    sessions: SessionRegistry,
+    idle_timeout: Duration,
+    max_duration: Duration,
+    warning: Duration,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut idle_warned = false;
+    let mut max_warned = false;
+
     loop {
-        tokio::select! {
-            Some(client_msg) = axum_socket.recv() => {
-                let client_msg = if let Ok(client_msg) = client_msg {
-                    tracing::debug!("Received from client: {:?}", client_msg);
-                    client_msg
-                } else {
-                    tracing::info!("Client disconnected, the msg isn't ok");
-                    return;
-                };
+        interval.tick().await;
+        let (age, idle_for) = (session.age(), session.idle_for());
 
-                if tx_web.send(client_msg).await.is_err() {
-                    tracing::info!("Failed to send message to channel");
-                }
-            },
-            Some(kube_msg) = rx_kube.recv() => {
-                tracing::debug!("Received from kubernetes: {}", kube_msg);
-                let kube_msg = Message::Text(kube_msg);
-                if axum_socket.send(kube_msg).await.is_err() {
-                    tracing::info!("Client disconnected, failed to send message");
+        let reason = if !max_duration.is_zero() && age >= max_duration {
+            CloseReason::MaxDuration
+        } else if !idle_timeout.is_zero() && idle_for >= idle_timeout {
+            CloseReason::IdleTimeout
+        } else {
+            if !max_duration.is_zero() && !max_warned && age + warning >= max_duration {
+                max_warned = true;
+                session.publish(build_terminal_notice(&format!(
+                    "The session reaches its maximum duration and ends in {}s.",
+                    (max_duration - age).as_secs()
+                )));
+            }
+            if !idle_timeout.is_zero() && idle_for + warning >= idle_timeout {
+                if !idle_warned {
+                    idle_warned = true;
+                    session.publish(build_terminal_notice(&format!(
+                        "The session is idle and ends in {}s unless you type something.",
+                        (idle_timeout - idle_for).as_secs()
+                    )));
                 }
+            } else {
+                idle_warned = false;
             }
-        }
+            continue;
+        };
+
+        tracing::info!("Ending session {}, {}", session.id, reason.as_str());
+        sessions.remove(&session.id);
+        session.end(reason);
+        return;
     }
 }
+
+/// Attaches another user to a running session, see `SessionRegistry`.
+pub async fn handle_join_socket(
+    mut axum_socket: WebSocket,
+    session: Arc<SharedSession>,
+    user: User,
+) {
+    let output = session.subscribe();
+    session.attach(&mut axum_socket, &user, output).await;
+}
@@ -0,0 +1,687 @@
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use common::{
+    anyhow,
+    axum::{self, http::StatusCode},
+    tokio::{
+        sync::{broadcast, mpsc, watch, Notify},
+        task::JoinHandle,
+        time::timeout,
+    },
+    tracing, uuid,
+};
+use context::config::AppConfig;
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use util::err::HttpErr;
+
+use crate::{
+    audit,
+    auth::User,
+    connector::ContainerCoords,
+    limits::{SessionLimiter, SessionPermit},
+    model::SessionInfo,
+    msg_handle::{build_terminal_notice, RESIZE_PREFIX, STD_INPUT_PREFIX},
+    pipeline::{Frame, WireProtocol},
+    protocol::{Signal, TermMessage},
+    signal::signal_session,
+};
+
+/// Frames kept for participants that fall behind before they start missing output.
+const SESSION_OUTPUT_CAPACITY: usize = 1024;
+
+/// How long kube output waits for a stalled owner before it is published regardless.
+const OWNER_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What to do with a client frame.
+enum MessageOutcome {
+    /// Goes on to kube, `stdin` tells keystrokes from resizes, only keystrokes count as activity.
+    Input {
+        input: Message,
+        stdin: bool,
+    },
+    Signal(Signal),
+    Reply(TermMessage),
+    Ignore,
+    Close,
+}
+
+/// Frames are shared between the scrollback and every participant.
+pub type SessionFrame = Arc<[u8]>;
+
+/// Why a session ended, sent to every participant in the close frame and audited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The owner disconnected and resuming is disabled.
+    OwnerLeft,
+    /// The owner didn't resume within the grace period.
+    NotResumed,
+    IdleTimeout,
+    MaxDuration,
+    /// The exec stream closed, the shell exited or couldn't be started.
+    Exited,
+}
+
+impl CloseReason {
+    /// Application close codes live in the 4000-4999 range.
+    pub fn code(&self) -> u16 {
+        match self {
+            CloseReason::OwnerLeft => 1000,
+            CloseReason::NotResumed => 4000,
+            CloseReason::IdleTimeout => 4001,
+            CloseReason::MaxDuration => 4002,
+            CloseReason::Exited => 4003,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::OwnerLeft => "owner_left",
+            CloseReason::NotResumed => "not_resumed",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::MaxDuration => "max_duration",
+            CloseReason::Exited => "exited",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            CloseReason::OwnerLeft => "The session owner disconnected.",
+            CloseReason::NotResumed => "The session wasn't resumed in time.",
+            CloseReason::IdleTimeout => "The session was idle for too long.",
+            CloseReason::MaxDuration => "The session reached its maximum duration.",
+            CloseReason::Exited => "The shell exited.",
+        }
+    }
+}
+
+/// Output frames with their sequence number, oldest first, bounded by their total size.
+#[derive(Default)]
+struct Scrollback {
+    frames: VecDeque<(u64, SessionFrame)>,
+    bytes: usize,
+    next_seq: u64,
+}
+
+/// A terminal session other users can watch, and drive once the owner grants them. It
+/// outlives the owner's connection for a grace period so the owner can resume it.
+pub struct SharedSession {
+    pub id: String,
+    /// Secret the owner resumes the session with, unlike `id` it is never shared.
+    pub token: String,
+    pub owner: String,
+    pub coords: ContainerCoords,
+    input: mpsc::Sender<Message>,
+    output: broadcast::Sender<(u64, SessionFrame)>,
+    scrollback: Mutex<Scrollback>,
+    scrollback_limit: usize,
+    /// Frames the owner may fall behind before kube output is held back.
+    output_window: u64,
+    owner_progress: Notify,
+    ended: watch::Sender<Option<CloseReason>>,
+    started: Instant,
+    /// Milliseconds after `started` of the last input from a driver.
+    last_input_ms: AtomicU64,
+    drivers: RwLock<BTreeSet<String>>,
+    participants: Mutex<Vec<String>>,
+    /// Sequence number of the first frame the owner hasn't received yet.
+    owner_next_seq: AtomicU64,
+    owner_attached: AtomicUsize,
+    /// Bumped whenever the owner attaches, a pending grace timer from an older epoch is void.
+    owner_epoch: AtomicU64,
+    /// Owner epoch and next sequence number when the owner was found stalled. Output isn't
+    /// held back for that connection again until it has received the frames up to there.
+    owner_stalled_at: Mutex<Option<(u64, u64)>>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    permits: Mutex<Vec<SessionPermit>>,
+    /// PID of the exec'd shell in the container, known once a signal was sent.
+    shell_pid: OnceLock<u32>,
+}
+
+impl SharedSession {
+    pub fn shell_pid(&self) -> Option<u32> {
+        self.shell_pid.get().copied()
+    }
+
+    pub fn record_shell_pid(&self, pid: u32) {
+        let _ = self.shell_pid.set(pid);
+    }
+
+    pub fn can_drive(&self, user: &User) -> bool {
+        user.0 == self.owner || self.drivers.read().unwrap().contains(&user.0)
+    }
+
+    /// Drivers and users currently attached to the session, the owner included.
+    pub fn is_participant(&self, user: &User) -> bool {
+        self.can_drive(user) || self.participants.lock().unwrap().contains(&user.0)
+    }
+
+    /// Publishes kube output once the attached owner is less than `output_window` frames
+    /// behind, holding back the exec stream meanwhile. A stalled owner is waited on for
+    /// `OWNER_STALL_TIMEOUT` at most, then left to lag like any other participant until it
+    /// catches up.
+    pub async fn publish_output(&self, frame: Frame) {
+        loop {
+            let progress = self.owner_progress.notified();
+            common::tokio::pin!(progress);
+            progress.as_mut().enable();
+            if !self.owner_behind() {
+                break;
+            }
+            if timeout(OWNER_STALL_TIMEOUT, progress).await.is_err() {
+                tracing::warn!("Owner of session {} is stalled", self.id);
+                let epoch = self.owner_epoch.load(Ordering::SeqCst);
+                let next_seq = self.scrollback.lock().unwrap().next_seq;
+                *self.owner_stalled_at.lock().unwrap() = Some((epoch, next_seq));
+                break;
+            }
+        }
+        self.publish(frame);
+    }
+
+    fn owner_behind(&self) -> bool {
+        if self.owner_attached.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+        let owner_next_seq = self.owner_next_seq.load(Ordering::SeqCst);
+        let mut stalled_at = self.owner_stalled_at.lock().unwrap();
+        if let Some((epoch, stalled_seq)) = *stalled_at {
+            if epoch == self.owner_epoch.load(Ordering::SeqCst) && owner_next_seq < stalled_seq {
+                return false;
+            }
+            *stalled_at = None;
+        }
+        drop(stalled_at);
+        let next_seq = self.scrollback.lock().unwrap().next_seq;
+        next_seq.saturating_sub(owner_next_seq) >= self.output_window
+    }
+
+    /// Sends a kube output frame (or notice) to every participant and keeps it for replay.
+    pub fn publish(&self, frame: Frame) {
+        let frame: SessionFrame = frame.into();
+        let mut scrollback = self.scrollback.lock().unwrap();
+        let seq = scrollback.next_seq;
+        scrollback.next_seq += 1;
+        scrollback.bytes += frame.len();
+        scrollback.frames.push_back((seq, frame.clone()));
+        while scrollback.bytes > self.scrollback_limit {
+            match scrollback.frames.pop_front() {
+                Some((_, dropped)) => scrollback.bytes -= dropped.len(),
+                None => break,
+            }
+        }
+        // Nobody listening is fine, the owner may be reconnecting.
+        let _ = self.output.send((seq, frame));
+    }
+
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id.clone(),
+            owner: self.owner.clone(),
+            namespace: self.coords.namespace.clone(),
+            pod: self.coords.pod.clone(),
+            container: self.coords.container.clone(),
+            drivers: self.drivers.read().unwrap().iter().cloned().collect(),
+            participants: self.participants.lock().unwrap().clone(),
+        }
+    }
+
+    /// Subscribe before output is produced to not miss it, the shell prompt in particular.
+    pub fn subscribe(&self) -> broadcast::Receiver<(u64, SessionFrame)> {
+        self.output.subscribe()
+    }
+
+    /// Subscribes and returns the frames the owner hasn't received yet, without gaps or
+    /// duplicates between the two.
+    pub fn resume(&self) -> (Vec<SessionFrame>, broadcast::Receiver<(u64, SessionFrame)>) {
+        let scrollback = self.scrollback.lock().unwrap();
+        let output = self.output.subscribe();
+        let next_seq = self.owner_next_seq.load(Ordering::SeqCst);
+
+        let mut missed = vec![];
+        if scrollback
+            .frames
+            .front()
+            .is_some_and(|(oldest, _)| *oldest > next_seq)
+        {
+            missed.push(
+                build_terminal_notice("Part of the output was dropped while disconnected.").into(),
+            );
+        }
+        missed.extend(
+            scrollback
+                .frames
+                .iter()
+                .filter(|(seq, _)| *seq >= next_seq)
+                .map(|(_, frame)| frame.clone()),
+        );
+        // The replay brings the owner up to date, don't hold back output meanwhile.
+        self.owner_next_seq
+            .store(scrollback.next_seq, Ordering::SeqCst);
+        (missed, output)
+    }
+
+    /// Holds the concurrency slot of the session until it ends.
+    pub fn hold(&self, permit: SessionPermit) {
+        self.permits.lock().unwrap().push(permit);
+    }
+
+    /// Keeps `task` running as long as the session and aborts it on `end`.
+    pub fn add_task(&self, task: JoinHandle<()>) {
+        self.tasks.lock().unwrap().push(task);
+    }
+
+    /// Returns the owner epoch once the owner's last connection is gone, `None` while the
+    /// owner is still attached elsewhere.
+    pub fn owner_detached(&self) -> Option<u64> {
+        if self.owner_attached.load(Ordering::SeqCst) > 0 {
+            return None;
+        }
+        Some(self.owner_epoch.load(Ordering::SeqCst))
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.ended.borrow().is_some()
+    }
+
+    pub fn age(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Handles a frame of the binary and text protocols, input goes on to kube as it is and
+    /// control frames stop here.
+    fn stream_input(client_msg: Message) -> MessageOutcome {
+        let stdin = match &client_msg {
+            Message::Binary(data) => match data.first() {
+                Some(&STD_INPUT_PREFIX) => true,
+                Some(&RESIZE_PREFIX) => false,
+                _ => return MessageOutcome::Ignore,
+            },
+            Message::Text(text) => !text.starts_with('9'),
+            Message::Close(_) => return MessageOutcome::Close,
+            Message::Ping(_) | Message::Pong(_) => return MessageOutcome::Ignore,
+        };
+        MessageOutcome::Input {
+            input: client_msg,
+            stdin,
+        }
+    }
+
+    fn record_input(&self) {
+        let now_ms = self.age().as_millis() as u64;
+        self.last_input_ms.store(now_ms, Ordering::SeqCst);
+    }
+
+    /// Sends input of `user` without waiting, for input fanned out to several sessions where
+    /// one stuck shell mustn't hold up the others. Dropped when `user` may not drive or the
+    /// shell is behind.
+    pub fn try_send_input(&self, user: &User, input: Message) {
+        let MessageOutcome::Input { input, stdin } = Self::stream_input(input) else {
+            return;
+        };
+        if !self.can_drive(user) {
+            tracing::debug!("Dropped input of {}, not a driver", user.0);
+            return;
+        }
+        match self.input.try_send(input) {
+            Ok(()) if stdin => self.record_input(),
+            Ok(()) => {}
+            Err(err) => tracing::warn!("Dropped input of session {}: {}", self.id, err),
+        }
+    }
+
+    /// Changes to `Some` once the session has ended.
+    pub fn ended(&self) -> watch::Receiver<Option<CloseReason>> {
+        self.ended.subscribe()
+    }
+
+    /// Time since a driver last sent input, output alone doesn't count as activity.
+    pub fn idle_for(&self) -> Duration {
+        let last_input = Duration::from_millis(self.last_input_ms.load(Ordering::SeqCst));
+        self.age().saturating_sub(last_input)
+    }
+
+    /// Handles a frame of the JSON protocol. Input goes on to kube as its frame, the binary
+    /// protocol's input.
+    fn handle_message(&self, client_msg: Message) -> MessageOutcome {
+        let text = match client_msg {
+            Message::Text(text) => text,
+            Message::Close(_) => return MessageOutcome::Close,
+            Message::Binary(_) => {
+                return MessageOutcome::Reply(TermMessage::error("expected a text frame"))
+            }
+            _ => return MessageOutcome::Ignore,
+        };
+        let message = match TermMessage::decode(&text) {
+            Ok(message) => message,
+            Err(err) => {
+                tracing::debug!("Rejected message in session {}: {}", self.id, err);
+                return MessageOutcome::Reply(TermMessage::error(format!(
+                    "malformed message, {err}"
+                )));
+            }
+        };
+        match message {
+            TermMessage::Ping => MessageOutcome::Reply(TermMessage::Pong),
+            TermMessage::Close { .. } => MessageOutcome::Close,
+            TermMessage::Signal { signal } => MessageOutcome::Signal(signal),
+            message => match message.to_kube_frame() {
+                Some(frame) => MessageOutcome::Input {
+                    input: Message::Binary(frame),
+                    stdin: matches!(message, TermMessage::Stdin { .. }),
+                },
+                None => MessageOutcome::Reply(TermMessage::error(
+                    "clients send stdin, resize, signal, ping or close",
+                )),
+            },
+        }
+    }
+
+    /// Relays output to `axum_socket` and its input to the session until either side goes
+    /// away. Input from users who may not drive is dropped.
+    pub async fn attach(
+        self: &Arc<Self>,
+        axum_socket: &mut WebSocket,
+        user: &User,
+        mut output: broadcast::Receiver<(u64, SessionFrame)>,
+    ) {
+        let is_owner = user.0 == self.owner;
+        let protocol = WireProtocol::of(axum_socket);
+        let mut ended = self.ended.subscribe();
+        // Signals run beside the relay, their failures come back here.
+        let (tx_reply, mut rx_reply) = mpsc::channel::<TermMessage>(4);
+        self.participants.lock().unwrap().push(user.0.clone());
+        if is_owner {
+            self.owner_attached.fetch_add(1, Ordering::SeqCst);
+            self.owner_epoch.fetch_add(1, Ordering::SeqCst);
+        } else {
+            audit::session_joined(self, user);
+            self.publish(build_terminal_notice(&format!(
+                "{} joined the session.",
+                user.0
+            )));
+        }
+
+        loop {
+            common::tokio::select! {
+                client_msg = axum_socket.recv() => {
+                    let Some(Ok(client_msg)) = client_msg else {
+                        tracing::info!("{} left session {}", user.0, self.id);
+                        break;
+                    };
+                    let outcome = match protocol {
+                        WireProtocol::Json => self.handle_message(client_msg),
+                        _ => Self::stream_input(client_msg),
+                    };
+                    let (client_msg, stdin) = match outcome {
+                            MessageOutcome::Input { input, stdin } => (input, stdin),
+                            MessageOutcome::Reply(reply) => {
+                                if axum_socket.send(reply.into()).await.is_err() {
+                                    break;
+                                }
+                                continue;
+                            }
+                            MessageOutcome::Signal(signal) => {
+                                let (session, user, tx_reply) =
+                                    (self.clone(), user.clone(), tx_reply.clone());
+                                common::tokio::spawn(async move {
+                                    if let Err(err) = signal_session(&session, &user, signal).await {
+                                        let _ = tx_reply.send(TermMessage::error(err)).await;
+                                    }
+                                });
+                                continue;
+                            }
+                            MessageOutcome::Ignore => continue,
+                            MessageOutcome::Close => {
+                                tracing::info!("{} closed session {}", user.0, self.id);
+                                break;
+                            }
+                    };
+                    if !self.can_drive(user) {
+                        tracing::debug!("Dropped input of {}, not a driver", user.0);
+                        continue;
+                    }
+                    // Input into a shell that is gone doesn't keep the session alive.
+                    if self.input.send(client_msg).await.is_err() {
+                        tracing::info!("Failed to send message to channel");
+                    } else if stdin {
+                        self.record_input();
+                    }
+                },
+                Some(reply) = rx_reply.recv() => {
+                    if axum_socket.send(reply.into()).await.is_err() {
+                        tracing::info!("Client disconnected, failed to send message");
+                        break;
+                    }
+                },
+                kube_msg = output.recv() => match kube_msg {
+                    Ok((seq, kube_msg)) => {
+                        if let Some(kube_msg) = protocol.encode(&kube_msg) {
+                            if axum_socket.send(kube_msg).await.is_err() {
+                                tracing::info!("Client disconnected, failed to send message");
+                                break;
+                            }
+                        }
+                        if is_owner {
+                            self.owner_next_seq.store(seq + 1, Ordering::SeqCst);
+                            self.owner_progress.notify_waiters();
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("{} missed {} frames of session {}", user.0, skipped, self.id);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = ended.changed() => {
+                    let reason = *ended.borrow();
+                    if let Some(reason) = reason {
+                        // The last output and the end notice were published ahead of the end.
+                        while let Ok((_, kube_msg)) = output.try_recv() {
+                            if let Some(kube_msg) = protocol.encode(&kube_msg) {
+                                let _ = axum_socket.send(kube_msg).await;
+                            }
+                        }
+                        if protocol == WireProtocol::Json {
+                            let close = TermMessage::Close {
+                                code: Some(reason.code()),
+                                reason: Some(reason.as_str().to_string()),
+                            };
+                            let _ = axum_socket.send(close.into()).await;
+                        }
+                        let close_frame = CloseFrame {
+                            code: reason.code(),
+                            reason: Cow::Borrowed(reason.message()),
+                        };
+                        let _ = axum_socket.send(Message::Close(Some(close_frame))).await;
+                    }
+                    break;
+                }
+            }
+        }
+
+        let mut participants = self.participants.lock().unwrap();
+        if let Some(pos) = participants.iter().position(|p| p == &user.0) {
+            participants.remove(pos);
+        }
+        drop(participants);
+        if is_owner {
+            self.owner_attached.fetch_sub(1, Ordering::SeqCst);
+            self.owner_progress.notify_waiters();
+        } else {
+            self.publish(build_terminal_notice(&format!(
+                "{} left the session.",
+                user.0
+            )));
+        }
+    }
+
+    /// Ends the session for every participant and stops the kube stream.
+    pub fn end(&self, reason: CloseReason) {
+        if self.is_ended() {
+            return;
+        }
+        // Published ahead of the close frame so it lands in the terminal.
+        self.publish(build_terminal_notice(&format!(
+            "The session has ended. {}",
+            reason.message()
+        )));
+        let first = self.ended.send_if_modified(|ended| {
+            if ended.is_some() {
+                return false;
+            }
+            *ended = Some(reason);
+            true
+        });
+        if !first {
+            return;
+        }
+        audit::session_ended(self, reason.as_str(), self.age().as_secs());
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        self.permits.lock().unwrap().clear();
+    }
+}
+
+#[derive(Clone)]
+pub struct SessionRegistry {
+    sessions: Arc<RwLock<HashMap<String, Arc<SharedSession>>>>,
+    limiter: Arc<SessionLimiter>,
+    scrollback_limit: usize,
+    output_window: u64,
+}
+
+impl SessionRegistry {
+    pub fn new(config: &AppConfig) -> Self {
+        Self {
+            sessions: Default::default(),
+            limiter: Arc::new(SessionLimiter::new(config)),
+            scrollback_limit: config.session_scrollback_bytes,
+            output_window: config.session_output_window.max(1),
+        }
+    }
+
+    /// Takes a concurrency slot for a new session, see `SessionLimiter`.
+    pub fn acquire(
+        &self,
+        user: &User,
+        coords: &ContainerCoords,
+    ) -> Result<SessionPermit, anyhow::Error> {
+        self.limiter.acquire(user, coords)
+    }
+
+    /// See `SessionLimiter::acquire_stream`.
+    pub fn acquire_stream(&self, user: &User) -> Result<SessionPermit, anyhow::Error> {
+        self.limiter.acquire_stream(user)
+    }
+
+    pub fn open_sessions(&self) -> usize {
+        self.limiter.open_sessions()
+    }
+
+    pub fn create(
+        &self,
+        owner: &User,
+        coords: ContainerCoords,
+        input: mpsc::Sender<Message>,
+    ) -> Arc<SharedSession> {
+        let (output, _) = broadcast::channel(SESSION_OUTPUT_CAPACITY);
+        let session = Arc::new(SharedSession {
+            id: uuid::Uuid::new_v4().to_string(),
+            token: uuid::Uuid::new_v4().simple().to_string(),
+            owner: owner.0.clone(),
+            coords,
+            input,
+            output,
+            scrollback: Default::default(),
+            scrollback_limit: self.scrollback_limit,
+            output_window: self.output_window,
+            owner_progress: Notify::new(),
+            ended: watch::channel(None).0,
+            started: Instant::now(),
+            last_input_ms: Default::default(),
+            drivers: Default::default(),
+            participants: Default::default(),
+            owner_next_seq: Default::default(),
+            owner_attached: Default::default(),
+            owner_epoch: Default::default(),
+            owner_stalled_at: Default::default(),
+            tasks: Default::default(),
+            permits: Default::default(),
+            shell_pid: Default::default(),
+        });
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(session.id.clone(), session.clone());
+        audit::session_started(&session);
+        session
+    }
+
+    pub fn get(&self, id: &str) -> Result<Arc<SharedSession>, anyhow::Error> {
+        self.sessions
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| {
+                let message = format!("Session {id} not found.");
+                HttpErr::new(StatusCode::NOT_FOUND, &message).into()
+            })
+    }
+
+    /// Looks up a session for its owner to resume, the token has to match.
+    pub fn get_for_resume(
+        &self,
+        id: &str,
+        token: &str,
+        user: &User,
+    ) -> Result<Arc<SharedSession>, anyhow::Error> {
+        let session = self.get(id)?;
+        if session.token != token || session.owner != user.0 {
+            let message = "Invalid session token.";
+            return Err(HttpErr::new(StatusCode::FORBIDDEN, message).into());
+        }
+        Ok(session)
+    }
+
+    pub fn remove(&self, id: &str) -> Option<Arc<SharedSession>> {
+        self.sessions.write().unwrap().remove(id)
+    }
+
+    /// Grants or revokes input for `driver`, only the owner may do so.
+    pub fn set_driver(
+        &self,
+        id: &str,
+        owner: &User,
+        driver: &str,
+        granted: bool,
+    ) -> Result<SessionInfo, anyhow::Error> {
+        let session = self.get(id)?;
+        if session.owner != owner.0 {
+            let message = "Only the session owner can change drivers.";
+            return Err(HttpErr::new(StatusCode::FORBIDDEN, message).into());
+        }
+
+        let changed = if granted {
+            session.drivers.write().unwrap().insert(driver.to_string())
+        } else {
+            session.drivers.write().unwrap().remove(driver)
+        };
+        if changed {
+            let notice = if granted {
+                format!("{driver} can now type in the session.")
+            } else {
+                format!("{driver} can no longer type in the session.")
+            };
+            session.publish(build_terminal_notice(&notice));
+        }
+        Ok(session.info())
+    }
+}
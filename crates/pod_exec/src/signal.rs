@@ -0,0 +1,119 @@
+//! Signals for the process of a terminal session. The exec stream can't carry one, so a second
+//! exec runs `kill` in the container. The session's shell is found by the session id in its
+//! environment and its PID is recorded. The foreground process group of its TTY is signalled,
+//! like Ctrl-C would, or the shell itself without a TTY.
+
+use common::{anyhow, axum::http::StatusCode, tokio::time::timeout, tracing};
+use kube::ServiceAccountToken;
+use std::time::Duration;
+use util::err::HttpErr;
+
+use crate::{
+    audit,
+    auth::User,
+    connector::{exec_command, SESSION_MARKER_ENV},
+    protocol::Signal,
+    session::SharedSession,
+};
+
+const SIGNAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Output kept from the `kill` exec, a PID or an error message.
+const SIGNAL_OUTPUT_LIMIT: usize = 4096;
+
+/// Arguments: the session marker, the signal name and the recorded PID if any. Prints the PID
+/// of the shell. Needs `cat`, `tr` and `grep` in the container, as coreutils or busybox ship
+/// them.
+pub const SIGNAL_SCRIPT: &str = r#"marker="$1" sig="$2" pid="$3"
+has_marker() { tr '\0' '\n' < "/proc/$1/environ" 2>/dev/null | grep -qxF "$marker"; }
+# comm, the second field, may contain spaces and ")", only what follows its last ")" is split.
+read_stat() {
+  stat=$(cat "/proc/$1/stat" 2>/dev/null) || return 1
+  set -- ${stat##*) }
+  ppid="$2" tpgid="$6"
+}
+if [ -z "$pid" ] || ! has_marker "$pid"; then
+  pid=
+  for dir in /proc/[0-9]*; do
+    candidate="${dir#/proc/}"
+    has_marker "$candidate" || continue
+    read_stat "$candidate" || continue
+    has_marker "$ppid" && continue
+    pid="$candidate"
+    break
+  done
+fi
+if [ -z "$pid" ]; then
+  echo "the session's shell isn't running" >&2
+  exit 1
+fi
+read_stat "$pid" || exit 1
+if [ "$tpgid" -gt 0 ]; then
+  kill -s "$sig" "-$tpgid" || exit 1
+else
+  kill -s "$sig" "$pid" || exit 1
+fi
+echo "$pid""#;
+
+impl Signal {
+    /// Name as `kill -s` takes it.
+    pub fn kill_name(&self) -> &'static str {
+        match self {
+            Signal::Sigint => "INT",
+            Signal::Sigterm => "TERM",
+            Signal::Sigkill => "KILL",
+        }
+    }
+}
+
+/// Sends `signal` to the foreground process of the session on behalf of `user`, who must be
+/// allowed to drive it. Fails with 409 when there is nothing to signal.
+pub async fn signal_session(
+    session: &SharedSession,
+    user: &User,
+    signal: Signal,
+) -> Result<(), anyhow::Error> {
+    if !session.can_drive(user) {
+        let message = "Only the owner and drivers can send signals.";
+        return Err(HttpErr::new(StatusCode::FORBIDDEN, message).into());
+    }
+    let command = [
+        "sh",
+        "-c",
+        SIGNAL_SCRIPT,
+        "kube-term-signal",
+        &format!("{SESSION_MARKER_ENV}={}", session.id),
+        signal.kill_name(),
+        &session
+            .shell_pid()
+            .map(|pid| pid.to_string())
+            .unwrap_or_default(),
+    ]
+    .map(String::from);
+
+    let sat = ServiceAccountToken::new();
+    let output = timeout(
+        SIGNAL_TIMEOUT,
+        exec_command(&sat, &session.coords, &command, SIGNAL_OUTPUT_LIMIT),
+    )
+    .await
+    .map_err(|_| {
+        let message = format!("Sending SIG{} timed out.", signal.kill_name());
+        HttpErr::new(StatusCode::GATEWAY_TIMEOUT, &message)
+    })??;
+    if output.exit_code != Some(0) {
+        let reason = match output.stderr.trim() {
+            "" => output.error.unwrap_or_default(),
+            stderr => stderr.to_string(),
+        };
+        let message = format!("Failed to send SIG{}: {reason}", signal.kill_name());
+        return Err(HttpErr::new(StatusCode::CONFLICT, &message).into());
+    }
+
+    audit::session_signalled(session, user, signal);
+    match output.stdout.trim().parse() {
+        Ok(pid) => session.record_shell_pid(pid),
+        Err(_) => tracing::warn!("Unexpected output of kill: {}", output.stdout),
+    }
+    Ok(())
+}
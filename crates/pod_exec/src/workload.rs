@@ -0,0 +1,255 @@
+use common::{anyhow, axum::http::StatusCode};
+use context::context::Context;
+use kube::{
+    k8s_openapi::{
+        api::{
+            apps::v1::{DaemonSet, Deployment, ReplicaSet, StatefulSet},
+            batch::v1::Job,
+            core::v1::Pod,
+        },
+        apimachinery::pkg::apis::meta::v1::{LabelSelector, ObjectMeta},
+    },
+    kube_runtime::{self, api::ListParams, Api},
+};
+use std::collections::HashSet;
+use util::err::HttpErr;
+
+use crate::model::{WorkloadCoords, WorkloadKind, WorkloadQuery, WorkloadSimpleInfo};
+
+pub async fn get_workload_list(
+    namespace: &str,
+    req: WorkloadQuery,
+    ctx: Context,
+) -> Result<Vec<WorkloadSimpleInfo>, anyhow::Error> {
+    let kinds = match req.kind {
+        Some(kind) => vec![kind],
+        None => WorkloadKind::ALL.to_vec(),
+    };
+    let client = ctx.kube_client.clone();
+    let lp = ListParams::default();
+
+    let mut workload_list = Vec::new();
+    for kind in kinds {
+        match kind {
+            WorkloadKind::Deployment => {
+                let deployments: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+                for d in deployments.list(&lp).await? {
+                    let spec = d.spec.unwrap_or_default();
+                    let status = d.status.unwrap_or_default();
+                    workload_list.push(WorkloadSimpleInfo::new(
+                        kind,
+                        d.metadata,
+                        Some(&spec.selector),
+                        spec.replicas.unwrap_or(1),
+                        status.ready_replicas.unwrap_or_default(),
+                    ));
+                }
+            }
+            WorkloadKind::StatefulSet => {
+                let statefulsets: Api<StatefulSet> = Api::namespaced(client.clone(), namespace);
+                for s in statefulsets.list(&lp).await? {
+                    let spec = s.spec.unwrap_or_default();
+                    let status = s.status.unwrap_or_default();
+                    workload_list.push(WorkloadSimpleInfo::new(
+                        kind,
+                        s.metadata,
+                        Some(&spec.selector),
+                        spec.replicas.unwrap_or(1),
+                        status.ready_replicas.unwrap_or_default(),
+                    ));
+                }
+            }
+            WorkloadKind::DaemonSet => {
+                let daemonsets: Api<DaemonSet> = Api::namespaced(client.clone(), namespace);
+                for d in daemonsets.list(&lp).await? {
+                    let spec = d.spec.unwrap_or_default();
+                    let status = d.status.unwrap_or_default();
+                    workload_list.push(WorkloadSimpleInfo::new(
+                        kind,
+                        d.metadata,
+                        Some(&spec.selector),
+                        status.desired_number_scheduled,
+                        status.number_ready,
+                    ));
+                }
+            }
+            WorkloadKind::Job => {
+                let jobs: Api<Job> = Api::namespaced(client.clone(), namespace);
+                for j in jobs.list(&lp).await? {
+                    let spec = j.spec.unwrap_or_default();
+                    let status = j.status.unwrap_or_default();
+                    workload_list.push(WorkloadSimpleInfo::new(
+                        kind,
+                        j.metadata,
+                        spec.selector.as_ref(),
+                        spec.parallelism.unwrap_or(1),
+                        status.ready.unwrap_or_default(),
+                    ));
+                }
+            }
+        }
+    }
+    Ok(workload_list)
+}
+
+/// Resolves a workload to the pods it currently owns. Deployments own their pods through
+/// ReplicaSets, the other kinds own them directly.
+pub async fn get_workload_pods(
+    ctx: &Context,
+    namespace: &str,
+    kind: WorkloadKind,
+    name: &str,
+) -> Result<Vec<Pod>, anyhow::Error> {
+    let client = ctx.kube_client.clone();
+    let (metadata, selector) = match kind {
+        WorkloadKind::Deployment => {
+            let d = Api::<Deployment>::namespaced(client.clone(), namespace)
+                .get(name)
+                .await
+                .map_err(|err| not_found_err(err, kind, name))?;
+            (d.metadata, d.spec.map(|s| s.selector))
+        }
+        WorkloadKind::StatefulSet => {
+            let s = Api::<StatefulSet>::namespaced(client.clone(), namespace)
+                .get(name)
+                .await
+                .map_err(|err| not_found_err(err, kind, name))?;
+            (s.metadata, s.spec.map(|s| s.selector))
+        }
+        WorkloadKind::DaemonSet => {
+            let d = Api::<DaemonSet>::namespaced(client.clone(), namespace)
+                .get(name)
+                .await
+                .map_err(|err| not_found_err(err, kind, name))?;
+            (d.metadata, d.spec.map(|s| s.selector))
+        }
+        WorkloadKind::Job => {
+            let j = Api::<Job>::namespaced(client.clone(), namespace)
+                .get(name)
+                .await
+                .map_err(|err| not_found_err(err, kind, name))?;
+            (j.metadata, j.spec.and_then(|s| s.selector))
+        }
+    };
+
+    let lp = match &selector {
+        Some(selector) => ListParams::default().labels(&label_selector_string(selector)),
+        None => ListParams::default(),
+    };
+    let replicasets = match kind {
+        WorkloadKind::Deployment => {
+            Api::<ReplicaSet>::namespaced(client.clone(), namespace)
+                .list(&lp)
+                .await?
+                .items
+        }
+        _ => Vec::new(),
+    };
+    let pods = Api::<Pod>::namespaced(client, namespace)
+        .list(&lp)
+        .await?
+        .items;
+    Ok(owned_pods(
+        kind,
+        metadata.uid.as_deref().unwrap_or_default(),
+        &replicasets,
+        pods,
+    ))
+}
+
+/// The pods among `pods` owned by the workload with `workload_uid`, through one of
+/// `replicasets` for a deployment.
+pub fn owned_pods(
+    kind: WorkloadKind,
+    workload_uid: &str,
+    replicasets: &[ReplicaSet],
+    pods: Vec<Pod>,
+) -> Vec<Pod> {
+    let workload_uids = HashSet::from([workload_uid.to_string()]);
+    let owner_uids: HashSet<String> = match kind {
+        WorkloadKind::Deployment => replicasets
+            .iter()
+            .filter(|rs| is_owned_by(&rs.metadata, &workload_uids))
+            .filter_map(|rs| rs.metadata.uid.clone())
+            .collect(),
+        _ => workload_uids,
+    };
+    pods.into_iter()
+        .filter(|p| is_owned_by(&p.metadata, &owner_uids))
+        .collect()
+}
+
+/// Picks the newest ready pod of the workload, see `newest_ready_pod`.
+pub async fn pick_ready_pod(ctx: &Context, coords: &WorkloadCoords) -> Result<Pod, anyhow::Error> {
+    let pods = get_workload_pods(ctx, &coords.namespace, coords.kind, &coords.name).await?;
+    newest_ready_pod(pods, coords)
+}
+
+/// The newest ready pod of the workload, fails with 409 when there is none, e.g. while the
+/// workload is scaled to zero or rolling out. Whether it runs `coords.container` is up to the
+/// caller, see `require_container`.
+pub fn newest_ready_pod(pods: Vec<Pod>, coords: &WorkloadCoords) -> Result<Pod, anyhow::Error> {
+    pods.into_iter()
+        .filter(|p| p.metadata.deletion_timestamp.is_none() && is_pod_ready(p))
+        .max_by(|a, b| {
+            a.metadata
+                .creation_timestamp
+                .cmp(&b.metadata.creation_timestamp)
+        })
+        .ok_or_else(|| {
+            let message = format!("{} {} has no ready pod.", coords.kind.as_str(), coords.name);
+            HttpErr::new(StatusCode::CONFLICT, &message).into()
+        })
+}
+
+pub fn is_pod_ready(pod: &Pod) -> bool {
+    pod.status
+        .as_ref()
+        .and_then(|s| s.conditions.as_ref())
+        .is_some_and(|conditions| {
+            conditions
+                .iter()
+                .any(|c| c.type_ == "Ready" && c.status == "True")
+        })
+}
+
+fn is_owned_by(metadata: &ObjectMeta, owner_uids: &HashSet<String>) -> bool {
+    metadata
+        .owner_references
+        .iter()
+        .flatten()
+        .any(|owner| owner_uids.contains(&owner.uid))
+}
+
+/// Renders a `LabelSelector` in the string form accepted by `ListParams::labels`.
+pub fn label_selector_string(selector: &LabelSelector) -> String {
+    let match_labels = selector
+        .match_labels
+        .iter()
+        .flatten()
+        .map(|(key, value)| format!("{key}={value}"));
+    let match_expressions = selector.match_expressions.iter().flatten().map(|expr| {
+        let values = expr.values.clone().unwrap_or_default().join(",");
+        match expr.operator.as_str() {
+            "In" => format!("{} in ({values})", expr.key),
+            "NotIn" => format!("{} notin ({values})", expr.key),
+            "Exists" => expr.key.clone(),
+            _ => format!("!{}", expr.key),
+        }
+    });
+    match_labels
+        .chain(match_expressions)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Maps a 404 of the workload lookup to one naming the workload.
+pub fn not_found_err(err: kube_runtime::Error, kind: WorkloadKind, name: &str) -> anyhow::Error {
+    match err {
+        kube_runtime::Error::Api(err) if err.code == 404 => {
+            let message = format!("{} {} not found.", kind.as_str(), name);
+            HttpErr::new(StatusCode::NOT_FOUND, &message).into()
+        }
+        err => err.into(),
+    }
+}
@@ -1,15 +1,56 @@
 #[cfg(test)]
 mod tests {
-    use common::axum::http::HeaderMap;
+    use common::axum::extract::ws::Message;
+    use common::axum::http::{HeaderMap, StatusCode};
+    use common::axum::response::IntoResponse as _;
     use common::reqwest::blocking::Client;
     use common::reqwest::header::AUTHORIZATION;
     use common::reqwest::Certificate;
-    use common::{anyhow, tracing};
-    use common::{base64, tokio};
+    use common::{anyhow, chrono, tracing};
+    use common::{base64, serde_json, tokio};
+    use context::config::AppConfig;
+    use kube::k8s_openapi::api::apps::v1::ReplicaSet;
+    use kube::k8s_openapi::api::core::v1::Pod;
+    use kube::k8s_openapi::apimachinery::pkg::apis::meta::v1::{
+        LabelSelector, LabelSelectorRequirement, Time,
+    };
+    use kube::kube_runtime::api::ObjectList;
+    use kube::kube_runtime::core::ErrorResponse;
+    use kube::kube_runtime::runtime::watcher;
     use kube::ServiceAccountToken;
-    use pod_exec::connector::{pod_exec_connector, PodExecParams, PodExecPath, PodExecUrl};
-    use pod_exec::msg_handle::{handle_websocket, stdin_reader};
+    use pod_exec::auth::User;
+    use pod_exec::connector::ContainerCoords;
+    use pod_exec::connector::{
+        encode_query_value, exit_code_from_status, is_dns1123_label, is_dns1123_subdomain,
+        pod_exec_connector, shell_quote, NamespaceCoords, PodExecParams, PodExecPath, PodExecUrl,
+        QueryBuilder, TerminalOptions,
+    };
+    use pod_exec::limits::RateLimiter;
+    use pod_exec::model::{
+        BroadcastReq, BroadcastResult, BroadcastSummary, ContainerKind, ContainerQuery,
+        ContainerStateKind, DebugQuery, MultiPaneQuery, NamespaceCursor, PaneFrame,
+        PodWatchEventType, TerminalQuery, WatchQuery, WorkloadCoords, WorkloadKind,
+    };
+    use pod_exec::msg_handle::{
+        build_terminal_notice, handle_binary_to_kube_channel, handle_websocket, stdin_reader,
+        MessageHandler as _,
+    };
+    use pod_exec::pipeline::{coalesce, encode_frame, WireProtocol, BINARY_PROTOCOL};
+    use pod_exec::pod_detail::parse_quantity;
+    use pod_exec::protocol::{Signal, TermMessage};
+    use pod_exec::selector::{Requirement, Selector};
+    use pod_exec::services::{
+        allowed_namespaces, collect_container_pages, debug_container_spec, debug_container_started,
+        default_container, pod_container_list, require_container, PodWatchState,
+        DEFAULT_CONTAINER_ANNOTATION,
+    };
+    use pod_exec::session::{CloseReason, SessionRegistry};
+    use pod_exec::signal::SIGNAL_SCRIPT;
+    use pod_exec::workload::{
+        is_pod_ready, label_selector_string, newest_ready_pod, not_found_err, owned_pods,
+    };
     use tokio::sync::mpsc;
+    use util::err::{AxumErr, HttpErr};
     use util::url_https_builder;
 
     #[test]
@@ -38,6 +79,1323 @@ mod tests {
         println!("{input:?}")
     }
 
+    #[test]
+    fn pod_container_list_with_status() -> Result<(), anyhow::Error> {
+        let pod: Pod =
+            serde_json::from_str(include_str!("../../../common/kube/tests/pod_example.json"))?;
+
+        let container_list = pod_container_list(&pod);
+        assert_eq!(container_list.len(), 1);
+
+        let container = &container_list[0];
+        assert_eq!(container.container.container.as_deref(), Some("web-term"));
+        assert_eq!(container.container_kind, ContainerKind::Container);
+        assert_eq!(container.container_state.state, ContainerStateKind::Running);
+        assert_eq!(container.restart_count, 2);
+        assert!(container.ready);
+        assert!(container.executable);
+        assert!(container.started_at.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn container_query_matches() -> Result<(), anyhow::Error> {
+        let pod: Pod =
+            serde_json::from_str(include_str!("../../../common/kube/tests/pod_example.json"))?;
+        let container = &pod_container_list(&pod)[0];
+
+        let query = |search: Option<&str>, image: Option<&str>| ContainerQuery {
+            search: search.map(str::to_string),
+            image: image.map(str::to_string),
+            ..Default::default()
+        };
+        assert!(query(None, None).matches(container));
+        assert!(query(Some("WEB-TERM"), None).matches(container));
+        assert!(query(Some("559fdfcd"), Some("ubuntu")).matches(container));
+        assert!(!query(Some("payments"), None).matches(container));
+        assert!(!query(None, Some("alpine")).matches(container));
+        Ok(())
+    }
+
+    #[test]
+    fn kubernetes_names_are_validated() {
+        assert!(is_dns1123_label("web-term"));
+        assert!(is_dns1123_label(&"a".repeat(63)));
+        assert!(!is_dns1123_label(&"a".repeat(64)));
+        for name in [
+            "",
+            "Web",
+            "-web",
+            "web-",
+            "web_term",
+            "web.term",
+            "web%2Fterm",
+            "..",
+        ] {
+            assert!(!is_dns1123_label(name), "{name:?}");
+        }
+        assert!(is_dns1123_subdomain("web-term-559fdfcd.0"));
+        assert!(!is_dns1123_subdomain("web..term"));
+        assert!(is_dns1123_subdomain(&format!("{}a", "a.".repeat(126))));
+        assert!(!is_dns1123_subdomain(&format!("{}a", "a.".repeat(127))));
+
+        let coords = |namespace: &str, pod: &str, container: &str| ContainerCoords {
+            namespace: namespace.to_string(),
+            pod: pod.to_string(),
+            container: container.to_string(),
+        };
+        assert!(coords("default", "web-term-559fdfcd", "web-term")
+            .validate()
+            .is_ok());
+        assert!(coords("default", "web-term-559fdfcd", "")
+            .validate()
+            .is_ok());
+        for invalid in [
+            coords("kube system", "web-term", "web-term"),
+            coords("default", "../secrets", "web-term"),
+            coords("default", "web-term", "Web"),
+        ] {
+            let err = invalid.validate().unwrap_err();
+            let err = err.downcast_ref::<HttpErr>().unwrap();
+            assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn exec_requires_an_existing_container() -> Result<(), anyhow::Error> {
+        let pod: Pod =
+            serde_json::from_str(include_str!("../../../common/kube/tests/pod_example.json"))?;
+        assert!(require_container(&pod, "web-term").is_ok());
+
+        let err = require_container(&pod, "sidecar").unwrap_err();
+        let err = err.downcast_ref::<HttpErr>().unwrap();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+        assert!(
+            err.message.ends_with("it has: web-term."),
+            "{}",
+            err.message
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn default_container_like_kubectl() -> Result<(), anyhow::Error> {
+        let mut pod: Pod =
+            serde_json::from_str(include_str!("../../../common/kube/tests/pod_example.json"))?;
+        assert_eq!(default_container(&pod)?, "web-term");
+
+        let spec = pod.spec.as_mut().unwrap();
+        let mut sidecar = spec.containers[0].clone();
+        sidecar.name = "sidecar".to_string();
+        spec.containers.push(sidecar);
+        let err = default_container(&pod).unwrap_err();
+        let err = err.downcast_ref::<HttpErr>().unwrap();
+        assert_eq!(err.status, StatusCode::CONFLICT);
+        assert!(
+            err.message.ends_with("web-term, sidecar."),
+            "{}",
+            err.message
+        );
+
+        let annotate = |pod: &mut Pod, container: &str| {
+            pod.metadata
+                .annotations
+                .get_or_insert_with(Default::default)
+                .insert(
+                    DEFAULT_CONTAINER_ANNOTATION.to_string(),
+                    container.to_string(),
+                );
+        };
+        annotate(&mut pod, "sidecar");
+        assert_eq!(default_container(&pod)?, "sidecar");
+        annotate(&mut pod, "removed");
+        assert!(default_container(&pod).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn debug_container_spec_from_query() {
+        let req = DebugQuery {
+            image: None,
+            target_container_name: Some("web-term".to_string()),
+            shell: Some("sh".to_string()),
+        };
+        let spec = debug_container_spec("debugger-1", req, "busybox:1.36");
+        assert_eq!(spec.name, "debugger-1");
+        assert_eq!(spec.image.as_deref(), Some("busybox:1.36"));
+        assert_eq!(spec.target_container_name.as_deref(), Some("web-term"));
+        assert_eq!((spec.stdin, spec.tty), (Some(true), Some(true)));
+
+        let req = DebugQuery {
+            image: Some("nicolaka/netshoot".to_string()),
+            ..Default::default()
+        };
+        let spec = debug_container_spec("debugger-1", req, "busybox:1.36");
+        assert_eq!(spec.image.as_deref(), Some("nicolaka/netshoot"));
+        assert_eq!(spec.target_container_name, None);
+    }
+
+    #[test]
+    fn debug_container_start_fails_fast() -> Result<(), anyhow::Error> {
+        let pod = |state: serde_json::Value| -> Pod {
+            serde_json::from_value(serde_json::json!({
+                "metadata": {"name": "web-term"},
+                "status": {
+                    "ephemeralContainerStatuses": [{
+                        "name": "debugger-1",
+                        "image": "busybox:1.36",
+                        "imageID": "",
+                        "ready": false,
+                        "restartCount": 0,
+                        "state": state,
+                    }]
+                }
+            }))
+            .unwrap()
+        };
+        let status = |state: serde_json::Value| {
+            let err = debug_container_started(Some(&pod(state)), "debugger-1")
+                .unwrap()
+                .unwrap_err();
+            err.downcast_ref::<HttpErr>().unwrap().status
+        };
+
+        let running = pod(serde_json::json!({"running": {}}));
+        assert!(debug_container_started(Some(&running), "debugger-1")
+            .unwrap()
+            .is_ok());
+        let creating = pod(serde_json::json!({"waiting": {"reason": "ContainerCreating"}}));
+        assert!(debug_container_started(Some(&creating), "debugger-1").is_none());
+        assert!(debug_container_started(Some(&running), "debugger-2").is_none());
+
+        let pull_failed = serde_json::json!({"waiting": {"reason": "ImagePullBackOff"}});
+        assert_eq!(status(pull_failed), StatusCode::UNPROCESSABLE_ENTITY);
+        let exited = serde_json::json!({"terminated": {"exitCode": 127, "reason": "Error"}});
+        assert_eq!(status(exited), StatusCode::CONFLICT);
+        assert!(debug_container_started(None, "debugger-1")
+            .unwrap()
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn filtered_pages_are_filled() -> Result<(), anyhow::Error> {
+        let pod: Pod =
+            serde_json::from_str(include_str!("../../../common/kube/tests/pod_example.json"))?;
+        let mut other = pod.clone();
+        other.metadata.name = Some("payments-7d9c".to_string());
+        other.spec.as_mut().unwrap().containers[0].name = "payments".to_string();
+        let page = |pod: &Pod, token: Option<&str>| -> ObjectList<Pod> {
+            serde_json::from_value(serde_json::json!({
+                "metadata": {"continue": token},
+                "items": [pod],
+            }))
+            .unwrap()
+        };
+        let pages = [page(&other, Some("t1")), page(&pod, None)];
+
+        let query = ContainerQuery {
+            search: Some("web-term".to_string()),
+            ..Default::default()
+        };
+        let mut tokens = Vec::new();
+        let container_rsp = collect_container_pages(&query, 1, None, |token| {
+            tokens.push(token);
+            let page = pages[tokens.len() - 1].clone();
+            async move { Ok::<_, anyhow::Error>(page) }
+        })
+        .await?;
+        assert_eq!(tokens, [None, Some("t1".to_string())]);
+        assert_eq!(container_rsp.container_list.len(), 1);
+        assert_eq!(container_rsp.pod_count, 2);
+        assert_eq!(container_rsp.page_token, None);
+
+        // Without container filters the first page is returned as it is.
+        let mut calls = 0;
+        let container_rsp = collect_container_pages(&ContainerQuery::default(), 1, None, |_| {
+            calls += 1;
+            let page = pages[0].clone();
+            async move { Ok::<_, anyhow::Error>(page) }
+        })
+        .await?;
+        assert_eq!(calls, 1);
+        assert_eq!(container_rsp.page_token.as_deref(), Some("t1"));
+        Ok(())
+    }
+
+    #[test]
+    fn watch_relist_reports_deleted_pods() -> Result<(), anyhow::Error> {
+        let pod: Pod =
+            serde_json::from_str(include_str!("../../../common/kube/tests/pod_example.json"))?;
+        let mut other = pod.clone();
+        other.metadata.name = Some("payments-7d9c".to_string());
+        other.metadata.uid = Some("payments-uid".to_string());
+
+        let mut state = PodWatchState::default();
+        let mut apply = |event| {
+            state
+                .apply(event)
+                .into_iter()
+                .map(|e| (e.r#type, e.pod))
+                .collect::<Vec<_>>()
+        };
+        assert!(apply(watcher::Event::Init).is_empty());
+        let added = apply(watcher::Event::InitApply(pod.clone()));
+        assert_eq!(added, [(PodWatchEventType::Added, pod_name(&pod))]);
+        assert!(apply(watcher::Event::InitDone).is_empty());
+        let added = apply(watcher::Event::Apply(other.clone()));
+        assert_eq!(added, [(PodWatchEventType::Added, pod_name(&other))]);
+
+        // `other` was deleted while the watch was down.
+        assert!(apply(watcher::Event::Init).is_empty());
+        let modified = apply(watcher::Event::InitApply(pod.clone()));
+        assert_eq!(modified, [(PodWatchEventType::Modified, pod_name(&pod))]);
+        let deleted = apply(watcher::Event::InitDone);
+        assert_eq!(deleted, [(PodWatchEventType::Deleted, pod_name(&other))]);
+        Ok(())
+    }
+
+    fn pod_name(pod: &Pod) -> String {
+        pod.metadata.name.clone().unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn exec_only_namespaces_are_reviewed_a_few_at_a_time() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let namespaces: Vec<String> = (0..40).map(|i| format!("team-{i}")).collect();
+        let (in_flight, max_in_flight) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let allowed = allowed_namespaces(&namespaces, |ns| {
+            let (in_flight, max_in_flight) = (&in_flight, &max_in_flight);
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                ns.ends_with('0')
+            }
+        })
+        .await;
+
+        let mut allowed: Vec<_> = allowed.into_iter().collect();
+        allowed.sort();
+        assert_eq!(allowed, ["team-0", "team-10", "team-20", "team-30"]);
+        let max_in_flight = max_in_flight.load(Ordering::SeqCst);
+        assert!((2..=8).contains(&max_in_flight), "{max_in_flight}");
+    }
+
+    #[test]
+    fn container_query_namespaces() {
+        let query = |ns: Option<&str>| ContainerQuery {
+            ns: ns.map(str::to_string),
+            ..Default::default()
+        };
+        assert_eq!(query(None).namespaces(), vec!["default"]);
+        assert_eq!(query(Some(" , ")).namespaces(), vec!["default"]);
+        assert_eq!(
+            query(Some("default, payments,,kube-system")).namespaces(),
+            vec!["default", "payments", "kube-system"]
+        );
+    }
+
+    #[test]
+    fn namespace_cursor_round_trip() -> Result<(), anyhow::Error> {
+        let cursor = NamespaceCursor {
+            index: 2,
+            token: Some("eyJ2IjoibWV0YS5rOHMuaW8vdjEifQ".to_string()),
+        };
+        assert_eq!(NamespaceCursor::decode(&cursor.encode())?, cursor);
+        assert!(NamespaceCursor::decode("not a cursor").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn http_err_status() {
+        let err: anyhow::Error = HttpErr::new(StatusCode::GONE, "Page token expired.").into();
+        let response = AxumErr::from(err).into_response();
+        assert_eq!(response.status(), StatusCode::GONE);
+
+        let response = AxumErr::from(anyhow::anyhow!("boom")).into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn terminal_notice_is_stdout_frame() -> Result<(), anyhow::Error> {
+        let notice = encode_frame(&build_terminal_notice("Pod web-term is being terminated"));
+        let data = notice.strip_prefix('1').unwrap_or_default();
+        let data = base64::Engine::decode(&base64::prelude::BASE64_STANDARD, data)?;
+
+        assert_eq!(data[0], 0x01);
+        assert!(String::from_utf8(data[1..].to_vec())?.contains("web-term is being terminated"));
+        Ok(())
+    }
+
+    #[test]
+    fn selector_parse() {
+        let selector = Selector::parse_labels("app=web-term, tier!=cache,canary,!legacy");
+        assert_eq!(
+            selector,
+            Some(Selector(vec![
+                Requirement::Equals("app".to_string(), "web-term".to_string()),
+                Requirement::NotEquals("tier".to_string(), "cache".to_string()),
+                Requirement::Exists("canary".to_string()),
+                Requirement::NotExists("legacy".to_string()),
+            ]))
+        );
+        assert_eq!(Selector::parse_labels("env in (prod,staging)"), None);
+        assert!(Selector::parse_fields("status.phase==Running").is_some());
+        assert_eq!(Selector::parse_fields("spec.restartPolicy=Always"), None);
+    }
+
+    #[test]
+    fn selector_matches_pod() -> Result<(), anyhow::Error> {
+        let pod: Pod =
+            serde_json::from_str(include_str!("../../../common/kube/tests/pod_example.json"))?;
+        let labels = |s: &str| Selector::parse_labels(s).unwrap_or_default();
+        let fields = |s: &str| Selector::parse_fields(s).unwrap_or_default();
+
+        assert!(labels("app=web-term").matches_pod_labels(&pod));
+        assert!(!labels("app!=web-term").matches_pod_labels(&pod));
+        assert!(labels("!legacy").matches_pod_labels(&pod));
+        assert!(fields("status.phase=Running,metadata.namespace=default").matches_pod_fields(&pod));
+        assert!(!fields("spec.nodeName=node-404").matches_pod_fields(&pod));
+        Ok(())
+    }
+
+    #[test]
+    fn workload_label_selector() {
+        let selector = LabelSelector {
+            match_labels: Some([("app".to_string(), "api".to_string())].into()),
+            match_expressions: Some(vec![
+                LabelSelectorRequirement {
+                    key: "tier".to_string(),
+                    operator: "In".to_string(),
+                    values: Some(vec!["web".to_string(), "worker".to_string()]),
+                },
+                LabelSelectorRequirement {
+                    key: "legacy".to_string(),
+                    operator: "DoesNotExist".to_string(),
+                    values: None,
+                },
+            ]),
+        };
+        assert_eq!(
+            label_selector_string(&selector),
+            "app=api,tier in (web,worker),!legacy"
+        );
+    }
+
+    #[test]
+    fn workload_kind_from_path() -> Result<(), anyhow::Error> {
+        let kind: WorkloadKind = serde_json::from_str("\"statefulset\"")?;
+        assert_eq!(kind, WorkloadKind::StatefulSet);
+        assert!(serde_json::from_str::<WorkloadKind>("\"StatefulSet\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pod_readiness() -> Result<(), anyhow::Error> {
+        let mut pod = example_pod()?;
+        assert!(is_pod_ready(&pod));
+        pod.status.as_mut().unwrap().conditions = None;
+        assert!(!is_pod_ready(&pod));
+        Ok(())
+    }
+
+    #[test]
+    fn query_supplied_names_are_validated() {
+        let is_bad_request = |result: Result<(), anyhow::Error>| {
+            result
+                .unwrap_err()
+                .downcast_ref::<HttpErr>()
+                .is_some_and(|err| err.status == StatusCode::BAD_REQUEST)
+        };
+
+        let containers = |ns: &str| ContainerQuery {
+            ns: Some(ns.to_string()),
+            ..Default::default()
+        };
+        assert!(containers("default, payments").validate().is_ok());
+        assert!(is_bad_request(containers("default,../secrets").validate()));
+
+        let watch = |ns: &str| WatchQuery {
+            ns: Some(ns.to_string()),
+            ..Default::default()
+        };
+        assert!(WatchQuery::default().validate().is_ok());
+        assert!(is_bad_request(watch("Payments").validate()));
+
+        let panes = |pods: &str, container: Option<&str>| MultiPaneQuery {
+            pods: Some(pods.to_string()),
+            container: container.map(str::to_string),
+            ..Default::default()
+        };
+        assert_eq!(
+            panes(" web-0, ,web-1", None).pod_names(),
+            vec!["web-0", "web-1"]
+        );
+        assert!(panes("web-0,web-1", Some("web")).validate().is_ok());
+        assert!(is_bad_request(panes("web-0,web/1", None).validate()));
+        assert!(is_bad_request(panes("web-0", Some("Web")).validate()));
+
+        let namespace = |namespace: &str| NamespaceCoords {
+            namespace: namespace.to_string(),
+        };
+        assert!(namespace("payments").validate().is_ok());
+        assert!(is_bad_request(namespace("kube system").validate()));
+
+        // The workload pod list route has no container.
+        assert!(workload_coords("").validate().is_ok());
+        assert!(is_bad_request(workload_coords("Web").validate()));
+    }
+
+    fn example_pod() -> Result<Pod, serde_json::Error> {
+        serde_json::from_str(include_str!("../../../common/kube/tests/pod_example.json"))
+    }
+
+    fn workload_coords(container: &str) -> WorkloadCoords {
+        WorkloadCoords {
+            namespace: "default".to_string(),
+            kind: WorkloadKind::Deployment,
+            name: "web-term".to_string(),
+            container: container.to_string(),
+        }
+    }
+
+    #[test]
+    fn workload_pods_are_owned_through_replicasets() -> Result<(), anyhow::Error> {
+        let pod = example_pod()?;
+        let replicaset: ReplicaSet = serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": "web-term-559fdfcd89",
+                "uid": "96848b70-27e9-4599-be12-41527055cf28",
+                "ownerReferences": [{
+                    "apiVersion": "apps/v1",
+                    "kind": "Deployment",
+                    "name": "web-term",
+                    "uid": "deployment-uid",
+                }],
+            }
+        }))?;
+        let mut stray = pod.clone();
+        stray.metadata.owner_references = None;
+        let pods = vec![pod.clone(), stray];
+
+        let owned = owned_pods(
+            WorkloadKind::Deployment,
+            "deployment-uid",
+            &[replicaset],
+            pods,
+        );
+        assert_eq!(owned.len(), 1);
+        assert_eq!(owned[0].metadata.uid, pod.metadata.uid);
+
+        let owned = owned_pods(
+            WorkloadKind::Deployment,
+            "other-uid",
+            &[],
+            vec![pod.clone()],
+        );
+        assert!(owned.is_empty());
+        // Other kinds own their pods directly.
+        let rs_uid = "96848b70-27e9-4599-be12-41527055cf28";
+        assert_eq!(
+            owned_pods(WorkloadKind::Job, rs_uid, &[], vec![pod]).len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn newest_ready_pod_is_picked() -> Result<(), anyhow::Error> {
+        let pod = example_pod()?;
+        let mut newer = pod.clone();
+        newer.metadata.name = Some("web-term-559fdfcd89-zzzzz".to_string());
+        newer.metadata.creation_timestamp = Some(Time(chrono::Utc::now()));
+        let mut newest_unready = newer.clone();
+        newest_unready.metadata.name = Some("web-term-559fdfcd89-unready".to_string());
+        newest_unready.status.as_mut().unwrap().conditions = None;
+
+        let pods = vec![pod, newer, newest_unready];
+        let picked = newest_ready_pod(pods, &workload_coords("web-term"))?;
+        assert_eq!(
+            picked.metadata.name.as_deref(),
+            Some("web-term-559fdfcd89-zzzzz")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn no_ready_pod_is_a_conflict() -> Result<(), anyhow::Error> {
+        let status = |result: Result<Pod, anyhow::Error>| {
+            result
+                .unwrap_err()
+                .downcast_ref::<HttpErr>()
+                .unwrap()
+                .status
+        };
+        let coords = workload_coords("web-term");
+        assert_eq!(
+            status(newest_ready_pod(Vec::new(), &coords)),
+            StatusCode::CONFLICT
+        );
+
+        let mut unready = example_pod()?;
+        unready.status.as_mut().unwrap().conditions = None;
+        assert_eq!(
+            status(newest_ready_pod(vec![unready], &coords)),
+            StatusCode::CONFLICT
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn workload_pod_must_run_the_container() -> Result<(), anyhow::Error> {
+        let coords = workload_coords("sidecar");
+        let picked = newest_ready_pod(vec![example_pod()?], &coords)?;
+        let err = require_container(&picked, &coords.container).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HttpErr>().unwrap().status,
+            StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn missing_workload_is_not_found() {
+        let err = kube::kube_runtime::Error::Api(ErrorResponse {
+            status: "Failure".to_string(),
+            message: "deployments.apps \"web-term\" not found".to_string(),
+            reason: "NotFound".to_string(),
+            code: 404,
+        });
+        let err = not_found_err(err, WorkloadKind::Deployment, "web-term");
+        let err = err.downcast_ref::<HttpErr>().unwrap();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+        assert_eq!(err.message, "deployment web-term not found.");
+    }
+
+    #[test]
+    fn broadcast_needs_a_command_and_a_selector() {
+        let req = |label_selector: &str, command: &[&str]| BroadcastReq {
+            label_selector: label_selector.to_string(),
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+            container: None,
+            parallelism: None,
+            timeout_secs: None,
+        };
+        assert!(req("app=web", &["uptime"]).validate().is_ok());
+        for invalid in [
+            req("", &["uptime"]),
+            req("  ", &["uptime"]),
+            req("app=web", &[]),
+        ] {
+            let err = invalid.validate().unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<HttpErr>().unwrap().status,
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    #[test]
+    fn broadcast_summary_counts_each_pod_once() {
+        let result = |pod: &str, exit_code: Option<i32>, error: Option<&str>| BroadcastResult {
+            pod: pod.to_string(),
+            container: "web-term".to_string(),
+            exit_code,
+            stdout: String::new(),
+            stderr: String::new(),
+            truncated: false,
+            error: error.map(str::to_string),
+        };
+        let mut summary = BroadcastSummary {
+            total: 4,
+            ..Default::default()
+        };
+        summary.record(&result("a", Some(0), None), false);
+        summary.record(&result("b", Some(1), None), false);
+        summary.record(&result("c", None, Some("connection refused")), false);
+        summary.record(&result("d", None, Some("Timed out.")), true);
+
+        assert_eq!(summary.succeeded, 1);
+        assert_eq!(summary.failed, ["b", "c"]);
+        assert_eq!(summary.timed_out, ["d"]);
+    }
+
+    #[test]
+    fn quantity_parsing() {
+        assert_eq!(parse_quantity("250m"), Some(0.25));
+        assert_eq!(parse_quantity("2"), Some(2.0));
+        assert_eq!(parse_quantity("1500000n"), Some(0.0015));
+        assert_eq!(parse_quantity("128Mi"), Some(134_217_728.0));
+        assert_eq!(parse_quantity("1G"), Some(1e9));
+        assert_eq!(parse_quantity("1e3"), Some(1e3));
+        assert_eq!(parse_quantity("12E6"), Some(12e6));
+        assert_eq!(parse_quantity("5e-3"), Some(5e-3));
+        assert_eq!(parse_quantity("1E"), Some(1e18));
+        assert_eq!(parse_quantity("1e"), None);
+        assert_eq!(parse_quantity("1e3m"), None);
+        assert_eq!(parse_quantity("12Zi"), None);
+        assert_eq!(parse_quantity(""), None);
+    }
+
+    #[test]
+    fn command_params_are_encoded() {
+        assert_eq!(encode_query_value("a-Z_0.~"), "a-Z_0.~");
+        assert_eq!(encode_query_value("ls -l&x=/"), "ls%20-l%26x%3D%2F");
+
+        let command = vec!["sh".to_string(), "-c".to_string(), "echo $HOME".to_string()];
+        let params = PodExecParams::default().get_pod_command_params("app", &command);
+        assert!(!params.stdin && !params.tty);
+        assert_eq!(
+            params.format(),
+            "?container=app&stdin=false&stdout=true&stderr=true&tty=false\
+             &command=sh&command=-c&command=echo%20%24HOME&pretty=true&follow=false"
+        );
+    }
+
+    #[test]
+    fn exec_url_resists_injection() {
+        assert_eq!(encode_query_value("é 漢"), "%C3%A9%20%E6%BC%A2");
+        assert_eq!(encode_query_value("a+b%20#c?"), "a%2Bb%2520%23c%3F");
+
+        let params = PodExecParams {
+            container: "app&tty=false#x".to_string(),
+            command: vec![
+                "echo".to_string(),
+                "a&command=rm".to_string(),
+                "50% off; #1".to_string(),
+                String::new(),
+            ],
+            ..Default::default()
+        };
+        let query = params.format();
+        assert!(query.starts_with("?container=app%26tty%3Dfalse%23x&"));
+        assert!(query.contains(
+            "&command=echo&command=a%26command%3Drm&command=50%25%20off%3B%20%231&command=&"
+        ));
+        assert_eq!(query.matches("&tty=").count(), 1);
+        assert_eq!(query.matches("command=").count(), 4);
+        assert!(!query.contains('#') && !query.contains(' '));
+
+        let coords = ContainerCoords {
+            namespace: "ns/../kube-system".to_string(),
+            pod: "web?x=1#y".to_string(),
+            container: "app".to_string(),
+        };
+        let url = PodExecUrl::default().get_exec_url("10.0.0.1", "443", &coords);
+        assert_eq!(
+            url.format(),
+            "wss://10.0.0.1:443/api/v1/namespaces/ns%2F..%2Fkube-system/pods/web%3Fx%3D1%23y/exec"
+        );
+
+        assert_eq!(
+            QueryBuilder::default()
+                .params("command", ["a", "b"])
+                .params("command", Vec::<String>::new())
+                .build(),
+            "?command=a&command=b"
+        );
+    }
+
+    #[test]
+    fn pane_frame_is_tagged() {
+        let frame = PaneFrame {
+            pod: "web-0".to_string(),
+            data: encode_frame(&build_terminal_notice("hi")),
+        };
+        let json: serde_json::Value = serde_json::to_value(&frame).unwrap();
+        assert_eq!(json["pod"], "web-0");
+        assert!(json["data"].as_str().unwrap().starts_with('1'));
+    }
+
+    #[test]
+    fn session_drivers_are_granted_by_owner() {
+        let sessions = SessionRegistry::new(&AppConfig::default());
+        let (tx_web, _rx_web) = mpsc::channel(1);
+        let owner = User("alice".to_string());
+        let guest = User("bob".to_string());
+        let session = sessions.create(&owner, ContainerCoords::default(), tx_web);
+
+        assert!(session.can_drive(&owner));
+        assert!(!session.can_drive(&guest));
+        assert!(!session.is_participant(&guest));
+        let err = sessions
+            .set_driver(&session.id, &guest, "bob", true)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HttpErr>().unwrap().status,
+            StatusCode::FORBIDDEN
+        );
+
+        let info = sessions
+            .set_driver(&session.id, &owner, "bob", true)
+            .unwrap();
+        assert_eq!(info.drivers, vec!["bob".to_string()]);
+        assert!(session.can_drive(&guest));
+        assert!(session.is_participant(&guest));
+        sessions
+            .set_driver(&session.id, &owner, "bob", false)
+            .unwrap();
+        assert!(!session.can_drive(&guest));
+
+        sessions.remove(&session.id);
+        assert!(sessions.get(&session.id).is_err());
+    }
+
+    #[test]
+    fn session_resume_replays_scrollback() {
+        let config = AppConfig {
+            session_scrollback_bytes: 8,
+            ..AppConfig::default()
+        };
+        let sessions = SessionRegistry::new(&config);
+        let (tx_web, _rx_web) = mpsc::channel(1);
+        let owner = User("alice".to_string());
+        let session = sessions.create(&owner, ContainerCoords::default(), tx_web);
+
+        session.publish(b"\x01aaaa".to_vec());
+        let (missed, _output) = session.resume();
+        assert_eq!(&*missed[0], b"\x01aaaa");
+
+        session.publish(b"\x01bbbb".to_vec());
+        session.publish(b"\x01cccc".to_vec());
+        let (missed, mut output) = session.resume();
+        assert_eq!(missed.len(), 2, "first frame evicted, gap notice added");
+        assert_eq!(
+            *missed[0],
+            *build_terminal_notice("Part of the output was dropped while disconnected.")
+        );
+        assert_eq!(&*missed[1], b"\x01cccc");
+
+        session.publish(b"\x01dddd".to_vec());
+        let (seq, frame) = output.try_recv().unwrap();
+        assert_eq!((seq, &*frame), (3, &b"\x01dddd"[..]));
+
+        let wrong = sessions.get_for_resume(&session.id, "nope", &owner);
+        assert!(wrong.is_err());
+        assert!(sessions
+            .get_for_resume(&session.id, &session.token, &owner)
+            .is_ok());
+    }
+
+    #[test]
+    fn session_end_is_idempotent() {
+        let sessions = SessionRegistry::new(&AppConfig::default());
+        let (tx_web, _rx_web) = mpsc::channel(1);
+        let owner = User("alice".to_string());
+        let session = sessions.create(&owner, ContainerCoords::default(), tx_web);
+        let mut output = session.subscribe();
+
+        assert!(!session.is_ended());
+        session.end(CloseReason::IdleTimeout);
+        session.end(CloseReason::MaxDuration);
+        assert!(session.is_ended());
+
+        let (_, notice) = output.try_recv().unwrap();
+        assert_eq!(
+            *notice,
+            *build_terminal_notice("The session has ended. The session was idle for too long.")
+        );
+        assert!(
+            output.try_recv().is_err(),
+            "only the first end is announced"
+        );
+        assert_eq!(CloseReason::IdleTimeout.code(), 4001);
+        assert_eq!(CloseReason::Exited.code(), 4003);
+    }
+
+    #[test]
+    fn pane_input_does_not_wait_on_a_full_session() {
+        let sessions = SessionRegistry::new(&AppConfig::default());
+        let (tx_web, mut rx_web) = mpsc::channel(1);
+        let owner = User("alice".to_string());
+        let session = sessions.create(&owner, ContainerCoords::default(), tx_web);
+
+        session.try_send_input(&owner, Message::Binary(b"\x00a".to_vec()));
+        session.try_send_input(&owner, Message::Binary(b"\x00b".to_vec()));
+        session.try_send_input(&User("bob".to_string()), Message::Binary(b"\x00c".to_vec()));
+
+        assert_eq!(
+            rx_web.try_recv().unwrap(),
+            Message::Binary(b"\x00a".to_vec())
+        );
+        assert!(
+            rx_web.try_recv().is_err(),
+            "full and non-driver input is dropped"
+        );
+
+        let mut ended = session.ended();
+        session.end(CloseReason::OwnerLeft);
+        assert!(ended.borrow_and_update().is_some());
+    }
+
+    #[test]
+    fn only_stdin_counts_as_activity() {
+        let sessions = SessionRegistry::new(&AppConfig::default());
+        let (tx_web, mut rx_web) = mpsc::channel(4);
+        let owner = User("alice".to_string());
+        let session = sessions.create(&owner, ContainerCoords::default(), tx_web);
+        let idle = std::time::Duration::from_millis(20);
+        std::thread::sleep(idle);
+
+        let resize = b"\x04{\"Width\":80,\"Height\":24}".to_vec();
+        session.try_send_input(&owner, Message::Binary(resize.clone()));
+        session.try_send_input(&owner, Message::Ping(vec![]));
+        session.try_send_input(&owner, Message::Binary(b"\x07".to_vec()));
+        assert!(session.idle_for() >= idle);
+        assert_eq!(rx_web.try_recv().unwrap(), Message::Binary(resize));
+        assert!(
+            rx_web.try_recv().is_err(),
+            "control frames stop at the session"
+        );
+
+        session.try_send_input(&owner, Message::Binary(b"\x00a".to_vec()));
+        assert!(session.idle_for() < idle);
+    }
+
+    #[tokio::test]
+    async fn stalled_owner_is_waited_on_once() {
+        use common::axum::{extract::WebSocketUpgrade, routing::get, Router};
+        use tokio::net::TcpListener;
+
+        let config = AppConfig {
+            session_output_window: 1,
+            ..AppConfig::default()
+        };
+        let sessions = SessionRegistry::new(&config);
+        let (tx_web, _rx_web) = mpsc::channel(1);
+        let owner = User("alice".to_string());
+        let session = sessions.create(&owner, ContainerCoords::default(), tx_web);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let attached = session.clone();
+        let app = Router::new().route(
+            "/",
+            get(|ws: WebSocketUpgrade| async move {
+                ws.on_upgrade(move |mut socket| async move {
+                    let output = attached.subscribe();
+                    attached.attach(&mut socket, &owner, output).await;
+                })
+            }),
+        );
+        tokio::spawn(async move { common::axum::serve(listener, app).await });
+        // The owner never reads, its socket buffers fill up after a few frames.
+        let (_owner_socket, _) = common::tokio_tungstenite::connect_async(format!("ws://{addr}"))
+            .await
+            .unwrap();
+
+        let started = std::time::Instant::now();
+        for _ in 0..12 {
+            session.publish_output(vec![1; 1 << 20]).await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= std::time::Duration::from_secs(5), "{elapsed:?}");
+        assert!(elapsed < std::time::Duration::from_secs(10), "{elapsed:?}");
+    }
+
+    #[test]
+    fn input_into_an_exited_shell_is_not_activity() {
+        let sessions = SessionRegistry::new(&AppConfig::default());
+        let (tx_web, rx_web) = mpsc::channel(4);
+        let owner = User("alice".to_string());
+        let session = sessions.create(&owner, ContainerCoords::default(), tx_web);
+        drop(rx_web);
+        let idle = std::time::Duration::from_millis(20);
+        std::thread::sleep(idle);
+
+        session.try_send_input(&owner, Message::Binary(b"\x00a".to_vec()));
+        assert!(session.idle_for() >= idle);
+    }
+
+    #[test]
+    fn session_limits_per_user_and_pod() {
+        let config = AppConfig {
+            max_sessions_per_user: 2,
+            max_sessions_per_pod: 3,
+            ..AppConfig::default()
+        };
+        let sessions = SessionRegistry::new(&config);
+        let pod = |name: &str| ContainerCoords {
+            namespace: "default".to_string(),
+            pod: name.to_string(),
+            container: "app".to_string(),
+        };
+        let (alice, bob) = (User("alice".to_string()), User("bob".to_string()));
+
+        let first = sessions.acquire(&alice, &pod("web-0")).unwrap();
+        let _second = sessions.acquire(&alice, &pod("web-1")).unwrap();
+        let err = sessions.acquire(&alice, &pod("web-2")).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HttpErr>().unwrap().status,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        drop(first);
+        let _third = sessions.acquire(&alice, &pod("web-0")).unwrap();
+
+        let _b1 = sessions.acquire(&bob, &pod("web-0")).unwrap();
+        let _b2 = sessions.acquire(&bob, &pod("web-0")).unwrap();
+        let carol = User("carol".to_string());
+        assert!(sessions.acquire(&carol, &pod("web-0")).is_err());
+        assert_eq!(sessions.open_sessions(), 4);
+
+        // Watches and broadcasts only count per user.
+        assert!(sessions.acquire_stream(&alice).is_err());
+        let watch = sessions.acquire_stream(&carol).unwrap();
+        assert_eq!(sessions.open_sessions(), 5);
+        drop(watch);
+        assert_eq!(sessions.open_sessions(), 4);
+    }
+
+    #[test]
+    fn rate_limiter_token_bucket() {
+        let limiter = RateLimiter::new(0.5, 2.0);
+        assert!(limiter.check("alice").is_ok());
+        assert!(limiter.check("alice").is_ok());
+        assert_eq!(limiter.check("alice"), Err(2));
+        assert!(limiter.check("bob").is_ok());
+        assert!(RateLimiter::new(0.0, 1.0).check("alice").is_ok());
+    }
+
+    #[test]
+    fn stdout_frames_are_coalesced() {
+        let (tx, mut rx) = mpsc::channel(8);
+        for frame in [&b"\x01b"[..], b"\x01c", b"\x02err", b"\x01d"] {
+            tx.try_send(frame.to_vec()).unwrap();
+        }
+
+        let mut frame = b"\x01a".to_vec();
+        let pending = coalesce(&mut frame, &mut rx, 1024);
+        assert_eq!(frame, b"\x01abc");
+        assert_eq!(pending.as_deref(), Some(&b"\x02err"[..]));
+
+        let mut frame = rx.try_recv().unwrap();
+        assert_eq!(coalesce(&mut frame, &mut rx, 1024), None);
+        assert_eq!(frame, b"\x01d");
+
+        tx.try_send(b"\x01efg".to_vec()).unwrap();
+        let mut frame = b"\x01ab".to_vec();
+        let pending = coalesce(&mut frame, &mut rx, 4);
+        assert_eq!(frame, b"\x01ab", "merging would exceed the limit");
+        assert_eq!(pending.as_deref(), Some(&b"\x01efg"[..]));
+    }
+
+    #[tokio::test]
+    async fn empty_kube_frames_are_ignored() {
+        let (tx, mut rx) = mpsc::channel(8);
+        assert!(!handle_binary_to_kube_channel(Vec::new(), &tx, 0, None).await);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn wire_protocol_from_subprotocol() {
+        use common::axum::http::HeaderValue;
+
+        let binary = HeaderValue::from_static(BINARY_PROTOCOL);
+        assert_eq!(
+            WireProtocol::from_header(Some(&binary)),
+            WireProtocol::Binary
+        );
+        let text = HeaderValue::from_static("echo-protocol");
+        assert_eq!(WireProtocol::from_header(Some(&text)), WireProtocol::Text);
+        assert_eq!(WireProtocol::from_header(None), WireProtocol::Text);
+
+        let frame = b"\x01hi";
+        assert_eq!(
+            WireProtocol::Binary.encode(frame),
+            Some(Message::Binary(frame.to_vec()))
+        );
+        assert_eq!(
+            WireProtocol::Text.encode(frame),
+            Some(Message::Text("1AWhp".to_string()))
+        );
+        let status = br#"{"metadata":{},"status":"Success"}"#;
+        let status = [&[0x03], &status[..]].concat();
+        assert_eq!(WireProtocol::Text.encode(&status), None);
+        assert_eq!(
+            WireProtocol::Json.encode(&status),
+            Some(Message::Text(
+                r#"{"exit_code":0,"message":null,"type":"status","v":1}"#.to_string()
+            ))
+        );
+
+        let stdin = Message::Binary(b"\x00ls\r".to_vec());
+        assert_eq!(stdin.binary_frame(), Some(b"\x00ls\r".to_vec()));
+        let resize = Message::Binary([&[0x04], &br#"{"Width":80,"Height":24}"#[..]].concat());
+        assert!(resize.binary_frame().is_some());
+        assert_eq!(Message::Binary(b"\x01out".to_vec()).binary_frame(), None);
+        assert_eq!(Message::Text("0bHM=".to_string()).binary_frame(), None);
+    }
+
+    #[test]
+    fn term_messages_are_parsed_strictly() {
+        let stdin = TermMessage::decode(r#"{"v":1,"type":"stdin","data":"bHMK"}"#).unwrap();
+        assert_eq!(stdin.to_kube_frame(), Some(b"\x00ls\n".to_vec()));
+        let resize = TermMessage::decode(r#"{"v":1,"type":"resize","rows":24,"cols":80}"#);
+        assert_eq!(
+            resize.unwrap().to_kube_frame(),
+            Some([&[0x04], &br#"{"Width":80,"Height":24}"#[..]].concat())
+        );
+        let signal = TermMessage::decode(r#"{"v":1,"type":"signal","signal":"SIGINT"}"#);
+        assert_eq!(
+            signal.unwrap(),
+            TermMessage::Signal {
+                signal: Signal::Sigint
+            }
+        );
+        assert_eq!(
+            TermMessage::decode(r#"{"v":1,"type":"ping"}"#).unwrap(),
+            TermMessage::Ping
+        );
+
+        for malformed in [
+            "",
+            "0bHMK",
+            "[]",
+            r#"{"type":"ping"}"#,
+            r#"{"v":2,"type":"ping"}"#,
+            r#"{"v":1,"type":"shout"}"#,
+            r#"{"v":1,"type":"stdin","data":"not base64!"}"#,
+            r#"{"v":1,"type":"stdin"}"#,
+            r#"{"v":1,"type":"resize","rows":24,"cols":-1}"#,
+            r#"{"v":1,"type":"resize","rows":24,"cols":80,"x":1}"#,
+            r#"{"v":1,"type":"resize","rows":0,"cols":80}"#,
+            r#"{"v":1,"type":"signal","signal":"SIGHUP"}"#,
+        ] {
+            assert!(TermMessage::decode(malformed).is_err(), "{malformed}");
+        }
+
+        let info = TermMessage::SessionInfo {
+            id: "id".to_string(),
+            token: "token".to_string(),
+            resume_within_secs: 60,
+            joinable: true,
+        };
+        assert_eq!(TermMessage::decode(&info.encode()).unwrap(), info);
+    }
+
+    #[test]
+    fn initial_size_from_query() {
+        let size = |rows, cols| {
+            TerminalQuery {
+                rows,
+                cols,
+                ..Default::default()
+            }
+            .resize_frame()
+        };
+        assert_eq!(
+            size(Some(24), Some(80)).unwrap(),
+            Some([&[0x04], &br#"{"Width":80,"Height":24}"#[..]].concat())
+        );
+        assert_eq!(size(None, None).unwrap(), None);
+        for (rows, cols) in [(Some(24), None), (None, Some(80)), (Some(0), Some(80))] {
+            let err = size(rows, cols).unwrap_err();
+            let err = err.downcast_ref::<HttpErr>().unwrap();
+            assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn terminal_command_from_config_and_query() {
+        let config = AppConfig {
+            session_term: "xterm".to_string(),
+            session_lang: Some("C.UTF-8".to_string()),
+            session_env: vec![("KUBECONFIG".to_string(), "/kube/config".to_string())],
+            session_workdir: None,
+            ..AppConfig::default()
+        };
+        let query = TerminalQuery {
+            term: Some("xterm-256color".to_string()),
+            env: Some("A=1, B=x y=z".to_string()),
+            ..Default::default()
+        };
+        let options = TerminalOptions::new("bash", &query, &config).unwrap();
+        assert_eq!(
+            options.command(Some("id")),
+            [
+                "env",
+                "TERM=xterm-256color",
+                "LANG=C.UTF-8",
+                "KUBECONFIG=/kube/config",
+                "A=1",
+                "B=x y=z",
+                "KUBE_TERM_SESSION=id",
+                "bash"
+            ]
+        );
+        let params = PodExecParams::default().get_pod_exec_params(
+            &ContainerCoords::default(),
+            &options,
+            None,
+        );
+        assert!(params
+            .format()
+            .contains("command=env&command=TERM%3Dxterm-256color&command=LANG%3DC.UTF-8"));
+
+        let workdir = "/srv/it's here; rm -rf / $(id) `id`";
+        let query = TerminalQuery {
+            workdir: Some(workdir.to_string()),
+            ..Default::default()
+        };
+        let command = TerminalOptions::new("bash", &query, &config)
+            .unwrap()
+            .command(None);
+        assert_eq!(
+            command[command.len() - 3..],
+            [
+                "sh",
+                "-c",
+                r#"cd -- '/srv/it'\''s here; rm -rf / $(id) `id`' && exec 'bash'"#
+            ]
+        );
+
+        for query in [
+            TerminalQuery {
+                env: Some("1A=x".to_string()),
+                ..Default::default()
+            },
+            TerminalQuery {
+                env: Some("A-B=x".to_string()),
+                ..Default::default()
+            },
+            TerminalQuery {
+                env: Some("NO_VALUE".to_string()),
+                ..Default::default()
+            },
+            TerminalQuery {
+                workdir: Some("/tmp\0".to_string()),
+                ..Default::default()
+            },
+        ] {
+            let err = TerminalOptions::new("bash", &query, &config).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<HttpErr>().unwrap().status,
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    #[test]
+    fn shell_quote_round_trips() {
+        use std::process::Command;
+
+        for value in [
+            "plain",
+            "",
+            "it's",
+            "'",
+            "a b\tc",
+            "$(id) `id` $HOME",
+            "\\ \" ; *",
+            "é\n",
+        ] {
+            let output = Command::new("sh")
+                .args(["-c", &format!("printf %s {}", shell_quote(value))])
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8_lossy(&output.stdout), value);
+        }
+    }
+
+    #[test]
+    fn legacy_text_frames() {
+        use common::base64::{prelude::BASE64_STANDARD, Engine as _};
+
+        assert_eq!(
+            TermMessage::decode_legacy("0bHMK").unwrap(),
+            TermMessage::Stdin {
+                data: b"ls\n".to_vec()
+            }
+        );
+        let resize = BASE64_STANDARD.encode(r#"{"type":"resize","data":{"rows":24,"columns":80}}"#);
+        assert_eq!(
+            TermMessage::decode_legacy(&format!("9{resize}")).unwrap(),
+            TermMessage::Resize { rows: 24, cols: 80 }
+        );
+        for malformed in ["", "0not base64!", "9bm90IGpzb24=", "9e30="] {
+            assert!(
+                TermMessage::decode_legacy(malformed).is_err(),
+                "{malformed}"
+            );
+        }
+    }
+
+    #[test]
+    fn signal_script_kills_marked_process() {
+        use std::os::unix::process::ExitStatusExt as _;
+        use std::process::{Child, Command, Stdio};
+
+        /// Kills the shell when an assertion fails before it was signalled.
+        struct KillOnDrop(Child);
+        impl Drop for KillOnDrop {
+            fn drop(&mut self) {
+                let _ = self.0.kill();
+                let _ = self.0.wait();
+            }
+        }
+
+        // A name with spaces and ")" shifts the fields of /proc/<pid>/stat when split naively.
+        let dir = std::env::temp_dir().join(format!("kube-term-signal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sleep = dir.join("s) 9 9 9 9 9 9");
+        let _ = std::fs::remove_file(&sleep);
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let sleep_bin = std::env::split_paths(&path)
+            .map(|dir| dir.join("sleep"))
+            .find(|bin| bin.exists())
+            .unwrap();
+        std::os::unix::fs::symlink(sleep_bin, &sleep).unwrap();
+
+        // A session of its own, without a controlling terminal the shell itself is signalled.
+        let spawned = Command::new("setsid")
+            .arg("env")
+            .arg("KUBE_TERM_SESSION=signal-test")
+            .arg(&sleep)
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let mut shell = KillOnDrop(spawned);
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let kill = |pid: &str| {
+            Command::new("sh")
+                .args(["-c", SIGNAL_SCRIPT, "kube-term-signal"])
+                .args(["KUBE_TERM_SESSION=signal-test", "TERM", pid])
+                .stderr(Stdio::piped())
+                .output()
+                .unwrap()
+        };
+        let output = kill("");
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            shell.0.id().to_string()
+        );
+        assert_eq!(shell.0.wait().unwrap().signal(), Some(15));
+
+        let output = kill(&shell.0.id().to_string());
+        assert!(!output.status.success(), "the recorded shell is gone");
+    }
+
+    #[test]
+    fn exec_status_exit_code() {
+        let status = |json: &str| serde_json::from_str(json).unwrap();
+        assert_eq!(
+            exit_code_from_status(&status(r#"{"metadata":{},"status":"Success"}"#)),
+            Some(0)
+        );
+        let failure = r#"{"metadata":{},"status":"Failure","reason":"NonZeroExitCode",
+            "details":{"causes":[{"reason":"ExitCode","message":"3"}]}}"#;
+        assert_eq!(exit_code_from_status(&status(failure)), Some(3));
+        let error = r#"{"metadata":{},"status":"Failure","message":"container not found"}"#;
+        assert_eq!(exit_code_from_status(&status(error)), None);
+    }
+
     #[test]
     fn rquest_tls() -> Result<(), anyhow::Error> {
         let _ = logger::logger_trace::init_logger("test_tls", false);
@@ -72,8 +1430,8 @@ mod tests {
             port: String::from(&sat.kube_port),
             path: PodExecPath {
                 base_path: String::from("/api/v1"),
-                namespace: String::from("/namespaces/default"),
-                pod: String::from("/pods/web-term-ffc789c85-v88qc"),
+                namespace: String::from("default"),
+                pod: String::from("web-term-ffc789c85-v88qc"),
                 tail_path: String::from("/exec"),
             },
         };
@@ -83,7 +1441,7 @@ mod tests {
             stdout: true,
             stderr: true,
             tty: true,
-            command: "bash".to_string(),
+            command: vec!["bash".to_string()],
             pretty: true,
             follow: true,
         };
@@ -107,7 +1465,10 @@ mod tests {
             }
         };
         while let Some(msg) = rx_ws.recv().await {
-            tracing::info!("Received from kubernetes: {}", msg);
+            tracing::info!(
+                "Received from kubernetes: {}",
+                String::from_utf8_lossy(&msg)
+            );
         }
     }
 }
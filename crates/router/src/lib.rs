@@ -1,7 +1,7 @@
 use axum::{routing::get, Router};
 use common::{
     axum::{
-        self,
+        self, middleware,
         routing::{on, MethodFilter},
         Extension,
     },
@@ -9,7 +9,15 @@ use common::{
 };
 
 use context::context::Context;
-use pod_exec::{container_list, handler, ns_list};
+use pod_exec::{
+    broadcast, container_list, debug_handler, default_container_handler, handler,
+    limits::{rate_limit, RateLimiter},
+    metrics, multi_pane_handler, ns_list, pod_detail, ready,
+    session::SessionRegistry,
+    session_driver_grant, session_driver_revoke, session_info, session_join_handler,
+    session_resume_handler, session_signal, watch_handler, workload_handler, workload_list,
+    workload_pod_list,
+};
 
 pub async fn init_router() -> Router {
     let ctx = Context::new()
@@ -19,13 +27,74 @@ pub async fn init_router() -> Router {
         })
         .unwrap();
 
-    Router::new()
-        .route("/health", get(|| async { "Hello, World!" }))
+    // Listing, watching and broadcasting hit the API server hardest, these routes are rate
+    // limited per user.
+    let list_routes = Router::new()
+        .route("/watch", on(MethodFilter::GET, watch_handler))
+        .route(
+            "/namespace/:namespace/broadcast",
+            on(MethodFilter::POST, broadcast),
+        )
         .route("/container", on(MethodFilter::GET, container_list))
         .route("/namespace", on(MethodFilter::GET, ns_list))
+        .route(
+            "/namespace/:namespace/workload",
+            on(MethodFilter::GET, workload_list),
+        )
+        .route(
+            "/namespace/:namespace/workload/:kind/:name",
+            on(MethodFilter::GET, workload_pod_list),
+        )
+        .route_layer(middleware::from_fn(rate_limit));
+
+    Router::new()
+        .route("/health", get(|| async { "Hello, World!" }))
+        .route("/ready", get(ready))
+        .route("/metrics", get(metrics))
+        .route("/session/:id", on(MethodFilter::GET, session_info))
+        .route(
+            "/session/:id/join",
+            on(MethodFilter::GET, session_join_handler),
+        )
+        .route(
+            "/session/:id/resume",
+            on(MethodFilter::GET, session_resume_handler),
+        )
+        .route(
+            "/session/:id/driver/:user",
+            on(MethodFilter::PUT, session_driver_grant)
+                .on(MethodFilter::DELETE, session_driver_revoke),
+        )
+        .route(
+            "/session/:id/signal/:signal",
+            on(MethodFilter::POST, session_signal),
+        )
+        .route(
+            "/namespace/:namespace/pod/:pod",
+            on(MethodFilter::GET, pod_detail),
+        )
         .route(
             "/namespace/:namespace/pod/:pod/container/:container",
             on(MethodFilter::GET, handler),
         )
+        .route(
+            "/namespace/:namespace/pod/:pod/exec",
+            on(MethodFilter::GET, default_container_handler),
+        )
+        .route(
+            "/namespace/:namespace/pod/:pod/debug",
+            on(MethodFilter::GET, debug_handler),
+        )
+        .route(
+            "/namespace/:namespace/multi",
+            on(MethodFilter::GET, multi_pane_handler),
+        )
+        .route(
+            "/namespace/:namespace/:kind/:name/container/:container",
+            on(MethodFilter::GET, workload_handler),
+        )
+        .merge(list_routes)
+        .layer(Extension(RateLimiter::from_config(&ctx.config)))
+        .layer(Extension(SessionRegistry::new(&ctx.config)))
         .layer(Extension(ctx))
 }